@@ -0,0 +1,164 @@
+//! Window event latency against a large process table.
+//!
+//! Spawns a few thousand idle processes so /proc looks like a busy workstation, then replays a
+//! burst of window create and destroy events through the model. The same burst is replayed with
+//! a full process table refresh per event, which is what the model used to do, for comparison.
+//!
+//!     cargo bench --bench event_latency
+//!
+//! SESSIOND_BENCH_PROCESSES and SESSIOND_BENCH_EVENTS change the table size and event count.
+
+#![allow(clippy::needless_return)]
+
+use std::collections::BTreeSet;
+use std::env;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use sessiond::info::{Desktop, IcccmProperties, ProcessesWindowsInfo, WindowGeometry, WindowType};
+use sessiond::x11_client::X11WindowInformation;
+use sysinfo::{Pid, ProcessesToUpdate, System};
+use xcb::x::Window as X11Window;
+use xcb::XidNew;
+
+const FIRST_XID: u32 = 0x0400_0000;
+const OWNER_PROCESSES: usize = 16;
+
+fn env_or(name: &str, default: usize) -> usize {
+    return env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default);
+}
+
+fn spawn_idle_processes(count: usize) -> Vec<Child> {
+    let mut children = Vec::with_capacity(count);
+    for _ in 0..count {
+        match Command::new("sleep")
+            .arg("3600")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => children.push(child),
+            Err(err) => {
+                eprintln!("stopped at {} processes: {}", children.len(), err);
+                break;
+            }
+        }
+    }
+    return children;
+}
+
+fn window_information<'a>(window: &'a X11Window, owner: Pid) -> X11WindowInformation<'a> {
+    return X11WindowInformation {
+        x11_window: window,
+        x11_resource_id: xcb::Xid::resource_id(window),
+        x11_window_name: "benchmark".to_string(),
+        x11_desktop: Desktop::Number(0),
+        x11_desktop_name: "main".to_string(),
+        x11_geometry: WindowGeometry::default(),
+        x11_states: BTreeSet::new(),
+        x11_icccm: IcccmProperties::default(),
+        x11_window_type: WindowType::Normal,
+        x11_transient_for: None,
+        process_id: Some(owner),
+    };
+}
+
+fn report(label: &str, mut samples: Vec<Duration>) {
+    samples.sort();
+    let total: Duration = samples.iter().sum();
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    println!(
+        "{:<28} mean {:>9.1?}  p50 {:>9.1?}  p99 {:>9.1?}  max {:>9.1?}",
+        label,
+        total / samples.len() as u32,
+        percentile(50),
+        percentile(99),
+        samples[samples.len() - 1]
+    );
+}
+
+// Every window is created and destroyed once, spread round-robin over the owner processes.
+fn replay<F>(
+    windows: &[X11Window],
+    owners: &[Pid],
+    mut before_event: F,
+) -> (Vec<Duration>, Vec<Duration>)
+where
+    F: FnMut(),
+{
+    let mut model = ProcessesWindowsInfo::new();
+    let mut inserts = Vec::with_capacity(windows.len());
+    let mut removals = Vec::with_capacity(windows.len());
+
+    for (index, window) in windows.iter().enumerate() {
+        let info = window_information(window, owners[index % owners.len()]);
+        let started = Instant::now();
+        before_event();
+        model.insert(&info);
+        inserts.push(started.elapsed());
+    }
+    // The owners are all alive, a missing command line means it wasn't read at all.
+    assert!(
+        model
+            .procinfo
+            .keys()
+            .all(|process| !process.cmdline.is_empty()),
+        "window owners were recorded without a command line"
+    );
+    for window in windows.iter() {
+        let started = Instant::now();
+        before_event();
+        model.remove_window(xcb::Xid::resource_id(window));
+        removals.push(started.elapsed());
+    }
+
+    return (inserts, removals);
+}
+
+fn main() {
+    let process_count = env_or("SESSIOND_BENCH_PROCESSES", 2000);
+    let event_count = env_or("SESSIOND_BENCH_EVENTS", 500);
+
+    let mut children = spawn_idle_processes(process_count);
+    let owners: Vec<Pid> = children
+        .iter()
+        .take(OWNER_PROCESSES)
+        .map(|child| Pid::from_u32(child.id()))
+        .collect();
+    if owners.is_empty() {
+        eprintln!("could not spawn any processes");
+        return;
+    }
+    // `XidNew::new` is only unsafe in older xcb releases.
+    #[allow(unused_unsafe)]
+    let windows: Vec<X11Window> = (0..event_count as u32)
+        .map(|index| unsafe { X11Window::new(FIRST_XID + index) })
+        .collect();
+
+    let mut sysinfo = System::new();
+    sysinfo.refresh_processes(ProcessesToUpdate::All);
+    println!(
+        "{} processes in the table, {} windows created and destroyed",
+        sysinfo.processes().len(),
+        event_count
+    );
+
+    let (inserts, removals) = replay(&windows, &owners, || {});
+    report("insert (targeted)", inserts);
+    report("remove (targeted)", removals);
+
+    let (inserts, removals) = replay(&windows, &owners, || {
+        sysinfo.refresh_processes(ProcessesToUpdate::All);
+    });
+    report("insert (full refresh)", inserts);
+    report("remove (full refresh)", removals);
+
+    for child in children.iter_mut() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
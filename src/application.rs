@@ -1,14 +1,23 @@
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::fs;
+use std::os::fd::AsRawFd;
 
+use chrono::{DateTime, Local};
 use xcb;
+use xcb::Xid;
 
-use crate::info::ProcessesWindowsInfo;
+use crate::events::{self, WindowEvent};
+use crate::info::{Desktop, RemovedWindow};
+use crate::ipc::IpcServer;
+use crate::logging;
+use crate::state::DaemonState;
 use crate::x11_client::X11Client;
 
 pub enum ApplicationErrorType {
     X11Error,
     ConnectionError,
     ProtocolError,
+    IpcError,
 }
 
 pub struct ApplicationError {
@@ -18,23 +27,351 @@ pub struct ApplicationError {
 pub struct Application<'a> {
     x11_client: &'a X11Client<'a>,
 
-    proc_win_info: ProcessesWindowsInfo,
+    state: DaemonState,
+    wm_clients: HashSet<u32>,
 
-    is_running: Arc<Mutex<bool>>,
+    last_autosave: DateTime<Local>,
+    last_change: Option<DateTime<Local>>,
 }
 
 impl<'a> Application<'a> {
-    pub fn new(running: Arc<Mutex<bool>>, x11_client: &'a mut X11Client<'a>) -> Self {
-        return Application {
-            x11_client: x11_client.connect(),
-            proc_win_info: ProcessesWindowsInfo::new(),
-            is_running: running,
+    pub fn new(
+        state: DaemonState,
+        x11_client: &'a mut X11Client<'a>,
+    ) -> Result<Self, ApplicationError> {
+        let x11_client = match x11_client.connect() {
+            Ok(x11_client) => x11_client,
+            Err(err) => {
+                logging::error(&err.to_string());
+                return Err(ApplicationError {
+                    kind: ApplicationErrorType::ConnectionError,
+                    retcode: 14,
+                });
+            }
         };
+
+        return Ok(Application {
+            x11_client,
+            state,
+            wm_clients: HashSet::new(),
+            last_autosave: Local::now(),
+            last_change: None,
+        });
+    }
+
+    // Managed windows are the ones listed in _NET_CLIENT_LIST, so the model follows that list
+    // rather than raw CreateNotify events, which also fire for frames and override-redirect
+    // windows. Clients that can't be inspected yet are retried on the next list change.
+    fn sync_client_list(self: &mut Self) {
+        let clients = self.x11_client.get_wm_clients();
+        let current: HashSet<u32> = clients.iter().map(|w| w.resource_id()).collect();
+
+        let new_windows: Vec<xcb::x::Window> = clients
+            .iter()
+            .filter(|w| !self.wm_clients.contains(&w.resource_id()))
+            .copied()
+            .collect();
+
+        // The model stays available to the control socket while the X server is asked.
+        let windows_info = self.x11_client.track_windows(&new_windows);
+
+        let mut proc_win_info = self.state.proc_win_info.lock().unwrap();
+        for window_info in windows_info {
+            match window_info {
+                Ok(window_info) => {
+                    proc_win_info.insert(&window_info);
+                    self.wm_clients.insert(window_info.x11_resource_id);
+                    self.last_change = Some(Local::now());
+                }
+                Err(_) => {}
+            }
+        }
+
+        let gone: Vec<u32> = self.wm_clients.difference(&current).copied().collect();
+        let mut removed = Vec::new();
+        for xid in gone {
+            self.wm_clients.remove(&xid);
+            removed.extend(proc_win_info.remove_window(xid));
+        }
+        drop(proc_win_info);
+
+        for removed_window in removed {
+            self.window_removed(removed_window);
+        }
+    }
+
+    fn window_removed(self: &mut Self, removed: RemovedWindow) {
+        self.last_change = Some(Local::now());
+
+        if removed.last_window {
+            let process = removed.process;
+            logging::info(&format!(
+                "last window of process {} ({}) closed",
+                process
+                    .process_id
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                process.cmdline
+            ));
+            self.publish(WindowEvent::LastWindowClosed {
+                process_id: process.process_id,
+                cmdline: process.cmdline,
+            });
+        }
+    }
+
+    fn window_moved(self: &mut Self, event: WindowEvent) {
+        self.last_change = Some(Local::now());
+        self.publish(event);
+    }
+
+    fn publish(self: &Self, event: WindowEvent) {
+        self.state.events.publish(&event);
+
+        let hooks = self.state.config.lock().unwrap().hooks.clone();
+        let hook = match event {
+            WindowEvent::WindowMoved { .. } => hooks.window_moved,
+            WindowEvent::LastWindowClosed { .. } => hooks.last_window_closed,
+        };
+        if let Some(command) = hook {
+            events::run_hook(&command, &event);
+        }
+    }
+
+    // Window managers usually set the number of desktops and their names one after the other, so
+    // both are read again whichever of them changed.
+    fn desktops_changed(self: &mut Self) {
+        let mut desktops = self.x11_client.get_desktop_names();
+        desktops.resize(
+            self.x11_client.get_number_of_desktops() as usize,
+            String::new(),
+        );
+
+        let mut layout = self.state.layout.lock().unwrap();
+        if layout.desktops == desktops {
+            return;
+        }
+        layout.desktops = desktops;
+        self.state
+            .proc_win_info
+            .lock()
+            .unwrap()
+            .rename_desktops(&layout);
+        drop(layout);
+
+        self.last_change = Some(Local::now());
+    }
+
+    // Changes made while tracking was paused went unobserved, so the model is rebuilt from
+    // scratch instead of being patched up.
+    fn resync(self: &mut Self) {
+        *self.state.layout.lock().unwrap() = self.x11_client.get_display_layout();
+        self.wm_clients.clear();
+        self.state.proc_win_info.lock().unwrap().clear();
+        self.sync_client_list();
+    }
+
+    pub fn autosave(self: &mut Self) {
+        self.last_autosave = Local::now();
+        self.last_change = None;
+
+        // An empty model most likely means the windows were already closed on the way out of
+        // the X session, which is not worth pushing a good snapshot out of the history for.
+        if self.state.proc_win_info.lock().unwrap().procinfo.is_empty() {
+            return;
+        }
+
+        let config = self.state.config.lock().unwrap().clone();
+        let snapshot = self.state.snapshot();
+        match snapshot.save_autosave(&config.session_directory(), config.autosave.keep) {
+            Ok(path) => logging::debug(&format!("autosaved session to {}", path.display())),
+            Err(err) => logging::error(&format!("autosaving session: {}", err)),
+        }
+    }
+
+    // The interval is measured from the last autosave of any kind, so a burst of changes does not
+    // get followed by a redundant periodic snapshot right away.
+    fn next_autosave(self: &Self) -> Option<DateTime<Local>> {
+        let config = self.state.config.lock().unwrap();
+
+        let periodic = config.autosave.interval().map(|i| self.last_autosave + i);
+        let debounced = match (self.last_change, config.autosave.debounce()) {
+            (Some(changed), Some(debounce)) => Some(changed + debounce),
+            _ => None,
+        };
+
+        match (periodic, debounced) {
+            (Some(p), Some(d)) => return Some(p.min(d)),
+            (p, d) => return p.or(d),
+        }
+    }
+
+    fn wait_for_activity(self: &Self, deadline: Option<DateTime<Local>>) {
+        let _ = self.x11_client.x11_connection.flush();
+
+        let mut fds = [
+            libc::pollfd {
+                fd: self.x11_client.x11_connection.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.state.wakeup.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = match deadline {
+            Some(deadline) => (deadline - Local::now())
+                .num_milliseconds()
+                .clamp(0, libc::c_int::MAX as i64) as libc::c_int,
+            None => -1,
+        };
+        // EINTR and friends only mean another round through the loop.
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+
+        self.state.wakeup.drain();
+    }
+
+    fn handle_event(self: &mut Self, event: xcb::Event) {
+        let atoms = self.x11_client.ewmh_atoms();
+
+        match event {
+            xcb::Event::X(xcb::x::Event::CreateNotify(_ev)) => {}
+            xcb::Event::X(xcb::x::Event::DestroyNotify(ev)) => {
+                // The window is gone by now, so everything has to come from the model.
+                let xid = ev.window().resource_id();
+                if self.wm_clients.remove(&xid) {
+                    let removed = self.state.proc_win_info.lock().unwrap().remove_window(xid);
+                    if let Some(removed) = removed {
+                        self.window_removed(removed);
+                    }
+                }
+            }
+            xcb::Event::X(xcb::x::Event::ConfigureNotify(ev))
+                if self.wm_clients.contains(&ev.window().resource_id()) =>
+            {
+                match self.x11_client.get_window_geometry(&ev.window()) {
+                    Ok(geometry) => {
+                        self.state
+                            .proc_win_info
+                            .lock()
+                            .unwrap()
+                            .update_window(ev.window().resource_id(), |w| w.geometry = geometry);
+                    }
+                    Err(_) => {}
+                }
+            }
+            xcb::Event::X(xcb::x::Event::PropertyNotify(ev))
+                if ev.atom() == atoms._NET_CLIENT_LIST =>
+            {
+                self.sync_client_list();
+            }
+            xcb::Event::X(xcb::x::Event::PropertyNotify(ev))
+                if ev.atom() == atoms._NET_WM_STATE
+                    && self.wm_clients.contains(&ev.window().resource_id()) =>
+            {
+                let states = self.x11_client.get_window_states(&ev.window());
+                self.state
+                    .proc_win_info
+                    .lock()
+                    .unwrap()
+                    .update_window(ev.window().resource_id(), |w| w.states = states);
+            }
+            xcb::Event::X(xcb::x::Event::PropertyNotify(ev))
+                if ev.atom() == atoms._NET_WM_DESKTOP
+                    && self.wm_clients.contains(&ev.window().resource_id()) =>
+            {
+                let xid = ev.window().resource_id();
+                let desktop = self.x11_client.get_desktop_of_window(&ev.window());
+                let desktop_name = self.state.layout.lock().unwrap().desktop_name(desktop);
+
+                let mut proc_win_info = self.state.proc_win_info.lock().unwrap();
+                let process_id = proc_win_info.window_owner(xid).and_then(|p| p.process_id);
+                let moved = proc_win_info.update_window(xid, |w| {
+                    let from = w.desktop;
+                    w.desktop = desktop;
+                    w.desktop_name.clone_from(&desktop_name);
+                    return WindowEvent::WindowMoved {
+                        window_xid: xid,
+                        window_name: w.window_name.clone(),
+                        process_id,
+                        from,
+                        to: desktop,
+                        desktop_name,
+                    };
+                });
+                drop(proc_win_info);
+
+                // Window managers also set the property when they first place a window, which
+                // is no move.
+                match moved {
+                    Some(WindowEvent::WindowMoved { from, to, .. })
+                        if from == Desktop::Unknown && to != Desktop::Unknown =>
+                    {
+                        self.last_change = Some(Local::now())
+                    }
+                    Some(event @ WindowEvent::WindowMoved { from, to, .. }) if from != to => {
+                        self.window_moved(event)
+                    }
+                    _ => {}
+                }
+            }
+            // Titles change all the time in terminals and editors, they are picked up by the next
+            // periodic autosave rather than triggering one each.
+            xcb::Event::X(xcb::x::Event::PropertyNotify(ev))
+                if (ev.atom() == atoms._NET_WM_NAME || ev.atom() == xcb::x::ATOM_WM_NAME)
+                    && self.wm_clients.contains(&ev.window().resource_id()) =>
+            {
+                let title = self.x11_client.get_window_name(&ev.window());
+                self.state
+                    .proc_win_info
+                    .lock()
+                    .unwrap()
+                    .update_window(ev.window().resource_id(), |w| w.set_title(title));
+            }
+            xcb::Event::X(xcb::x::Event::PropertyNotify(ev))
+                if ev.window() == self.x11_client.root_window()
+                    && (ev.atom() == atoms._NET_DESKTOP_NAMES
+                        || ev.atom() == atoms._NET_NUMBER_OF_DESKTOPS) =>
+            {
+                self.desktops_changed();
+            }
+            xcb::Event::X(xcb::x::Event::PropertyNotify(ev))
+                if ev.window() == self.x11_client.root_window()
+                    && ev.atom() == atoms._NET_CURRENT_DESKTOP =>
+            {
+                self.state.layout.lock().unwrap().current_desktop =
+                    self.x11_client.get_current_desktop();
+            }
+            xcb::Event::X(xcb::x::Event::PropertyNotify(_ev)) => {}
+            _ => {}
+        }
     }
 
     pub fn run(self: &mut Self) -> Result<i32, ApplicationError> {
+        // Bound before anything else, so a second daemon gives up before scanning the display.
+        let ipc_server = match IpcServer::bind(self.state.clone()) {
+            Ok(server) => server,
+            Err(err) => {
+                logging::error(&format!("starting control socket: {}", err));
+                return Err(ApplicationError {
+                    kind: ApplicationErrorType::IpcError,
+                    retcode: 13,
+                });
+            }
+        };
+        let socket_path = ipc_server.path().to_path_buf();
+
+        let result = self.track(ipc_server);
+
+        let _ = fs::remove_file(socket_path);
+        return result;
+    }
+
+    fn track(self: &mut Self, ipc_server: IpcServer) -> Result<i32, ApplicationError> {
         let x11_conn = &self.x11_client.x11_connection;
-        
+
         let wm_client_list = self
             .x11_client
             .x11_connection
@@ -65,58 +402,89 @@ impl<'a> Application<'a> {
             self.proc_win_info.insert(&window_information.unwrap());
         }*/
 
-        for w in self.x11_client.get_wm_clients() {
-            let window_info = self.x11_client.get_window_information(&w).unwrap();
-            self.proc_win_info.insert(&window_info);
+        if self.x11_client.select_root_events().is_err() {
+            return Err(ApplicationError {
+                kind: ApplicationErrorType::X11Error,
+                retcode: 12,
+            });
         }
 
+        *self.state.layout.lock().unwrap() = self.x11_client.get_display_layout();
+        self.sync_client_list();
+        ipc_server.spawn();
 
-        for proc_windows_info_iter in self.proc_win_info.procinfo.iter() {
+        for proc_windows_info_iter in self.state.proc_win_info.lock().unwrap().procinfo.iter() {
             println!(
                 "Process '{}' with pid '{}' has windows with xids '{}'",
                 proc_windows_info_iter.0.cmdline,
-                proc_windows_info_iter.0.process_id,
+                proc_windows_info_iter
+                    .0
+                    .process_id
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
                 proc_windows_info_iter
                     .1
-                    .into_iter()
-                    .map(|w_i| w_i.window_xid.to_string())
+                    .keys()
+                    .map(|xid| xid.to_string())
                     .collect::<Vec<String>>()
                     .join(", ")
             );
         }
 
-        while *self.is_running.lock().unwrap() {
-            let event = match x11_conn.wait_for_event() {
-                Err(xcb::Error::Connection(_)) => {
-                    return Err(ApplicationError {
-                        kind: ApplicationErrorType::ConnectionError,
-                        retcode: 10,
-                    });
-                }
-                Err(xcb::Error::Protocol(_)) => {
-                    return Err(ApplicationError {
-                        kind: ApplicationErrorType::ProtocolError,
-                        retcode: 11,
-                    });
-                }
-                Ok(event) => event,
-            };
-
-            match event {
-                xcb::Event::X(xcb::x::Event::CreateNotify(ev)) => {
-                    let window = ev.window();
-                    self.proc_win_info
-                        .insert(&self.x11_client.get_window_information(&window).unwrap());
+        let mut was_paused = false;
+        while *self.state.is_running.lock().unwrap() {
+            // Replies waited for while handling events can pull further events off the socket,
+            // so the queue has to be empty before sleeping on the file descriptor.
+            loop {
+                let event = match x11_conn.poll_for_event() {
+                    Err(xcb::Error::Connection(_)) => {
+                        return Err(ApplicationError {
+                            kind: ApplicationErrorType::ConnectionError,
+                            retcode: 10,
+                        });
+                    }
+                    Err(xcb::Error::Protocol(_)) => {
+                        return Err(ApplicationError {
+                            kind: ApplicationErrorType::ProtocolError,
+                            retcode: 11,
+                        });
+                    }
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                };
+
+                self.state.statistics.lock().unwrap().events_processed += 1;
+                if !*self.state.is_paused.lock().unwrap() {
+                    self.handle_event(event);
                 }
-                xcb::Event::X(xcb::x::Event::DestroyNotify(ev)) => {
-                    let window = ev.window();
-                    self.proc_win_info
-                        .remove(&self.x11_client.get_window_information(&window).unwrap());
+            }
+
+            let is_paused = *self.state.is_paused.lock().unwrap();
+            if was_paused && !is_paused {
+                self.resync();
+            }
+            was_paused = is_paused;
+
+            if !*self.state.is_running.lock().unwrap() {
+                break;
+            }
+
+            // Nothing is autosaved while paused since the model no longer follows the display.
+            let mut deadline = None;
+            if !is_paused {
+                deadline = self.next_autosave();
+                if deadline.is_some_and(|d| d <= Local::now()) {
+                    self.autosave();
+                    deadline = self.next_autosave();
                 }
-                xcb::Event::X(xcb::x::Event::PropertyNotify(_ev)) => {}
-                _ => {}
             }
+            self.wait_for_activity(deadline);
         }
+
+        if self.state.config.lock().unwrap().autosave.on_shutdown {
+            self.autosave();
+        }
+
         return Ok(0);
     }
 }
@@ -0,0 +1,470 @@
+use std::process::ExitCode;
+
+use crate::events::WindowEvent;
+use crate::info::WindowType;
+use crate::ipc::{self, DaemonStatus, Request, Response};
+use crate::session::{ProcessSnapshot, SessionSummary};
+
+pub const USAGE: &str = "\
+Usage: sessiond <command> [options]
+
+Commands:
+    daemon [--foreground]    start the session daemon
+    list                     list tracked processes and their windows
+    save [NAME]              save the current session, optionally under NAME
+    restore [NAME]           restore the default session or the one saved as NAME
+    restore --autosave [N]   restore the newest autosave, or the Nth older one
+    sessions                 list named sessions
+    rename OLD NEW           rename a named session
+    delete NAME              delete a named session
+    status                   show daemon status
+    watch                    print window events as they happen
+    stop                     stop the running daemon
+
+Options:
+    --json                   print machine-readable output
+    -h, --help               print this help
+";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Daemon {
+        foreground: bool,
+    },
+    List,
+    Save {
+        name: Option<String>,
+    },
+    Restore {
+        name: Option<String>,
+        autosave: Option<usize>,
+    },
+    Sessions,
+    Rename {
+        from: String,
+        to: String,
+    },
+    Delete {
+        name: String,
+    },
+    Status,
+    Watch,
+    Stop,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub json: bool,
+}
+
+impl Cli {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut json = false;
+        let mut foreground = false;
+        let mut autosave = false;
+        let mut positional: Vec<&str> = Vec::new();
+
+        for arg in args.iter() {
+            match arg.as_str() {
+                "--json" => json = true,
+                "--foreground" => foreground = true,
+                "--autosave" => autosave = true,
+                "-h" | "--help" => {
+                    return Ok(Cli {
+                        command: Command::Help,
+                        json,
+                    })
+                }
+                flag if flag.starts_with('-') => {
+                    return Err(format!("unknown option '{}'", flag));
+                }
+                value => positional.push(value),
+            }
+        }
+
+        let (subcommand, rest) = match positional.split_first() {
+            Some((subcommand, rest)) => (*subcommand, rest),
+            None => return Err("no command given".to_string()),
+        };
+
+        if foreground && subcommand != "daemon" {
+            return Err("--foreground only applies to the daemon command".to_string());
+        }
+        if autosave && subcommand != "restore" {
+            return Err("--autosave only applies to the restore command".to_string());
+        }
+
+        let optional_name = |rest: &[&str]| -> Result<Option<String>, String> {
+            match rest {
+                [] => return Ok(None),
+                [name] => return Ok(Some(name.to_string())),
+                _ => return Err(format!("{} takes at most one session name", subcommand)),
+            }
+        };
+        let no_arguments = |rest: &[&str]| -> Result<(), String> {
+            if !rest.is_empty() {
+                return Err(format!("{} takes no arguments", subcommand));
+            }
+            return Ok(());
+        };
+
+        let command = match subcommand {
+            "daemon" => {
+                no_arguments(rest)?;
+                Command::Daemon { foreground }
+            }
+            "list" => {
+                no_arguments(rest)?;
+                Command::List
+            }
+            "save" => Command::Save {
+                name: optional_name(rest)?,
+            },
+            "restore" if autosave => {
+                let index = match optional_name(rest)? {
+                    Some(index) => match index.parse::<usize>() {
+                        Ok(index) => index,
+                        Err(_) => return Err(format!("'{}' is not an autosave number", index)),
+                    },
+                    None => 0,
+                };
+                Command::Restore {
+                    name: None,
+                    autosave: Some(index),
+                }
+            }
+            "restore" => Command::Restore {
+                name: optional_name(rest)?,
+                autosave: None,
+            },
+            "sessions" => {
+                no_arguments(rest)?;
+                Command::Sessions
+            }
+            "rename" => match rest {
+                [from, to] => Command::Rename {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                },
+                _ => return Err("rename takes the old and the new session name".to_string()),
+            },
+            "delete" => match rest {
+                [name] => Command::Delete {
+                    name: name.to_string(),
+                },
+                _ => return Err("delete takes one session name".to_string()),
+            },
+            "status" => {
+                no_arguments(rest)?;
+                Command::Status
+            }
+            "watch" => {
+                no_arguments(rest)?;
+                Command::Watch
+            }
+            "stop" => {
+                no_arguments(rest)?;
+                Command::Stop
+            }
+            "help" => Command::Help,
+            other => return Err(format!("unknown command '{}'", other)),
+        };
+
+        return Ok(Cli { command, json });
+    }
+}
+
+/// Runs one of the client commands against the daemon listening on the control socket.
+pub fn run_client(command: &Command, json: bool) -> ExitCode {
+    let request = match command {
+        Command::List => Request::List,
+        Command::Save { name } => Request::Save { name: name.clone() },
+        Command::Restore { name, autosave } => Request::Restore {
+            name: name.clone(),
+            autosave: *autosave,
+        },
+        Command::Sessions => Request::Sessions,
+        Command::Rename { from, to } => Request::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        },
+        Command::Delete { name } => Request::Delete { name: name.clone() },
+        Command::Status => Request::Status,
+        Command::Watch => return watch(json),
+        Command::Stop => Request::Shutdown,
+        Command::Daemon { .. } | Command::Help => return ExitCode::FAILURE,
+    };
+
+    let response = match ipc::send_request(&request) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!(
+                "sessiond: cannot reach daemon at {}: {}",
+                ipc::socket_path().display(),
+                err.message
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&response).unwrap());
+    } else {
+        print_response(&response);
+    }
+
+    match response {
+        Response::Error { .. } => return ExitCode::FAILURE,
+        _ => return ExitCode::SUCCESS,
+    }
+}
+
+fn watch(json: bool) -> ExitCode {
+    let result = ipc::subscribe(|event| {
+        if json {
+            println!("{}", serde_json::to_string(&event).unwrap());
+        } else {
+            print_event(&event);
+        }
+    });
+
+    match result {
+        Ok(_) => return ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!(
+                "sessiond: cannot watch daemon at {}: {}",
+                ipc::socket_path().display(),
+                err.message
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+}
+
+fn print_event(event: &WindowEvent) {
+    match event {
+        WindowEvent::WindowMoved {
+            window_xid,
+            window_name,
+            from,
+            to,
+            desktop_name,
+            ..
+        } => println!(
+            "0x{:08x}  moved from desktop {} to {} ({})  {}",
+            window_xid, from, to, desktop_name, window_name
+        ),
+        WindowEvent::LastWindowClosed {
+            process_id,
+            cmdline,
+        } => println!(
+            "{:>10}  closed its last window  {}",
+            process_id
+                .map(|pid| pid.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            cmdline
+        ),
+    }
+}
+
+fn print_response(response: &Response) {
+    match response {
+        Response::Processes { processes } => print_processes(processes),
+        Response::Saved {
+            path,
+            processes,
+            windows,
+        } => println!(
+            "Saved {} windows of {} processes to {}",
+            windows,
+            processes,
+            path.display()
+        ),
+        Response::Restored {
+            launched,
+            failed,
+            placed,
+            unmatched,
+            skipped,
+        } => {
+            println!(
+                "Launched {} processes, placed {} windows",
+                launched.len(),
+                placed
+            );
+            if *unmatched > 0 {
+                println!("{} windows did not appear before the timeout", unmatched);
+            }
+            if *skipped > 0 {
+                println!(
+                    "{} windows were skipped because their process was unknown",
+                    skipped
+                );
+            }
+            for cmdline in failed.iter() {
+                println!("Failed to launch: {}", cmdline);
+            }
+        }
+        Response::Sessions { sessions } => print_sessions(sessions),
+        Response::Renamed { from, to } => println!("Renamed session '{}' to '{}'", from, to),
+        Response::Deleted { name } => println!("Deleted session '{}'", name),
+        Response::Status(status) => print_status(status),
+        Response::Subscribed => println!("Subscribed to window events"),
+        Response::ShuttingDown => println!("Daemon is shutting down"),
+        Response::Error { message } => eprintln!("sessiond: {}", message),
+    }
+}
+
+fn print_processes(processes: &[ProcessSnapshot]) {
+    for process in processes.iter() {
+        match process.process_id {
+            Some(pid) => println!("{:>7}  {}", pid, process.cmdline),
+            None => println!("{:>7}  (unknown process)", "-"),
+        }
+        for window in process.windows.iter() {
+            let mut details = match window.icccm.wm_class.as_ref() {
+                Some(wm_class) => format!("  [{}]", wm_class.class),
+                None => String::new(),
+            };
+            if window.window_type != WindowType::Normal {
+                details.push_str(&format!("  ({:?})", window.window_type).to_lowercase());
+            }
+            if let Some(parent) = window.transient_for {
+                details.push_str(&format!("  (transient for 0x{:08x})", parent));
+            }
+            println!(
+                "         0x{:08x}  desktop {} ({})  {}{}",
+                window.window_xid, window.desktop, window.desktop_name, window.window_name, details
+            );
+            // The last entry is the current title printed above.
+            let earlier = window.title_history.len().saturating_sub(1);
+            for change in window.title_history[..earlier].iter().rev() {
+                println!(
+                    "                     was {}  {}",
+                    change.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                    change.title
+                );
+            }
+        }
+    }
+}
+
+fn print_sessions(sessions: &[SessionSummary]) {
+    if sessions.is_empty() {
+        println!("No named sessions");
+        return;
+    }
+
+    for session in sessions.iter() {
+        let layout = match session.layout.as_ref() {
+            Some(layout) => format!(
+                "{}.{}, {} desktops",
+                layout.display,
+                layout.screen,
+                layout.desktops.len()
+            ),
+            None => "unknown display".to_string(),
+        };
+        println!(
+            "{:<20}  {}  {:>3} windows  {:>3} processes  {}",
+            session.name,
+            session.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            session.windows,
+            session.processes,
+            layout
+        );
+    }
+}
+
+fn print_status(status: &DaemonStatus) {
+    println!("sessiond is running with pid {}", status.pid);
+    println!("started    {}", status.started.format("%Y-%m-%d %H:%M:%S"));
+    println!("uptime     {}s", status.uptime_seconds);
+    println!("events     {}", status.events_processed);
+    if status.paused {
+        println!("tracking   paused");
+    }
+    println!("processes  {}", status.processes);
+    println!("windows    {}", status.windows);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        return Cli::parse(&args);
+    }
+
+    #[test]
+    fn parses_commands_and_flags() {
+        let cli = parse(&["daemon", "--foreground"]).unwrap();
+        assert_eq!(cli.command, Command::Daemon { foreground: true });
+        assert!(!cli.json);
+
+        let cli = parse(&["--json", "list"]).unwrap();
+        assert_eq!(cli.command, Command::List);
+        assert!(cli.json);
+
+        assert_eq!(
+            parse(&["save", "work"]).unwrap().command,
+            Command::Save {
+                name: Some("work".to_string())
+            }
+        );
+        assert_eq!(parse(&["list", "--help"]).unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["frobnicate"]).is_err());
+        assert!(parse(&["list", "--verbose"]).is_err());
+        assert!(parse(&["list", "extra"]).is_err());
+        assert!(parse(&["save", "one", "two"]).is_err());
+        assert!(parse(&["list", "--foreground"]).is_err());
+    }
+
+    #[test]
+    fn parses_autosave_restore() {
+        assert_eq!(
+            parse(&["restore", "--autosave"]).unwrap().command,
+            Command::Restore {
+                name: None,
+                autosave: Some(0)
+            }
+        );
+        assert_eq!(
+            parse(&["restore", "--autosave", "2"]).unwrap().command,
+            Command::Restore {
+                name: None,
+                autosave: Some(2)
+            }
+        );
+        assert!(parse(&["restore", "--autosave", "latest"]).is_err());
+        assert!(parse(&["save", "--autosave"]).is_err());
+    }
+
+    #[test]
+    fn parses_session_management() {
+        assert_eq!(parse(&["sessions"]).unwrap().command, Command::Sessions);
+        assert_eq!(
+            parse(&["rename", "old", "new"]).unwrap().command,
+            Command::Rename {
+                from: "old".to_string(),
+                to: "new".to_string()
+            }
+        );
+        assert_eq!(
+            parse(&["delete", "old"]).unwrap().command,
+            Command::Delete {
+                name: "old".to_string()
+            }
+        );
+        assert!(parse(&["rename", "old"]).is_err());
+        assert!(parse(&["delete"]).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging::LogLevel;
+use crate::restore::DEFAULT_RESTORE_TIMEOUT;
+use crate::xdg;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+// Upper bound for durations given in seconds, far beyond anything useful and far below where
+// adding them to the current time overflows.
+const MAX_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug)]
+pub enum ConfigErrorType {
+    Io,
+    Parse,
+    Invalid,
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub kind: ConfigErrorType,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(kind: ConfigErrorType, message: String) -> Self {
+        return ConfigError { kind, message };
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:?}: {}", self.kind, self.message);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutosaveConfig {
+    /// Seconds between periodic snapshots, 0 disables them.
+    pub interval: u64,
+    /// Seconds of quiet after windows appear, disappear or change desktop before a snapshot is
+    /// written, 0 disables change-triggered snapshots.
+    pub debounce: u64,
+    /// Number of autosaves kept, the oldest one is dropped when a new one is written.
+    pub keep: usize,
+    /// Write one last snapshot when the daemon is asked to stop.
+    pub on_shutdown: bool,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        return AutosaveConfig {
+            interval: 300,
+            debounce: 10,
+            keep: 5,
+            on_shutdown: true,
+        };
+    }
+}
+
+impl AutosaveConfig {
+    pub fn interval(self: &Self) -> Option<chrono::Duration> {
+        if self.interval == 0 {
+            return None;
+        }
+        return Some(chrono::Duration::seconds(self.interval as i64));
+    }
+
+    pub fn debounce(self: &Self) -> Option<chrono::Duration> {
+        if self.debounce == 0 {
+            return None;
+        }
+        return Some(chrono::Duration::seconds(self.debounce as i64));
+    }
+}
+
+/// How restoring a session may change the number of desktops to the one it was saved with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DesktopPolicy {
+    /// Leave the number of desktops alone.
+    Keep,
+    /// Add desktops when the session had more, never remove any.
+    Grow,
+    /// Add or remove desktops until there are as many as in the session.
+    Match,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestoreConfig {
+    /// Seconds to wait for relaunched applications to map their windows.
+    pub timeout: u64,
+    pub desktops: DesktopPolicy,
+}
+
+impl Default for RestoreConfig {
+    fn default() -> Self {
+        return RestoreConfig {
+            timeout: DEFAULT_RESTORE_TIMEOUT.as_secs(),
+            desktops: DesktopPolicy::Grow,
+        };
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Log file, stderr when unset.
+    pub file: Option<PathBuf>,
+    pub level: LogLevel,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        return LoggingConfig {
+            file: None,
+            level: LogLevel::Info,
+        };
+    }
+}
+
+/// Shell commands run when something happens to a tracked window, see `events::run_hook`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub window_moved: Option<String>,
+    pub last_window_closed: Option<String>,
+}
+
+/// Leaves matching windows out of saved and restored sessions. Every pattern that is set has to
+/// occur in the corresponding value for the rule to match.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExcludeRule {
+    pub cmdline: Option<String>,
+    pub window_name: Option<String>,
+}
+
+impl ExcludeRule {
+    pub fn matches(self: &Self, cmdline: &str, window_name: &str) -> bool {
+        let cmdline_matches = match self.cmdline.as_ref() {
+            Some(pattern) => cmdline.contains(pattern.as_str()),
+            None => true,
+        };
+        let window_name_matches = match self.window_name.as_ref() {
+            Some(pattern) => window_name.contains(pattern.as_str()),
+            None => true,
+        };
+
+        return cmdline_matches && window_name_matches;
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub session_directory: Option<PathBuf>,
+    pub autosave: AutosaveConfig,
+    pub restore: RestoreConfig,
+    pub logging: LoggingConfig,
+    pub hooks: HooksConfig,
+    pub exclude: Vec<ExcludeRule>,
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        return xdg::config_directory().join(CONFIG_FILE_NAME);
+    }
+
+    /// A missing file yields the defaults; a file that exists but can't be read, parsed or
+    /// validated is an error so that a reload can keep the configuration already in use.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => {
+                return Err(ConfigError::new(
+                    ConfigErrorType::Io,
+                    format!("{}: {}", path.display(), err),
+                ))
+            }
+        };
+
+        let config: Config = match toml::from_str(&data) {
+            Ok(config) => config,
+            Err(err) => {
+                return Err(ConfigError::new(
+                    ConfigErrorType::Parse,
+                    format!("{}: {}", path.display(), err),
+                ))
+            }
+        };
+        config.validate()?;
+
+        return Ok(config);
+    }
+
+    fn validate(self: &Self) -> Result<(), ConfigError> {
+        if self.restore.timeout == 0 {
+            return Err(ConfigError::new(
+                ConfigErrorType::Invalid,
+                "restore.timeout must be at least one second".to_string(),
+            ));
+        }
+        if self.restore.timeout > MAX_SECONDS {
+            return Err(ConfigError::new(
+                ConfigErrorType::Invalid,
+                format!("restore.timeout can be at most {} seconds", MAX_SECONDS),
+            ));
+        }
+
+        if self.autosave.interval > MAX_SECONDS || self.autosave.debounce > MAX_SECONDS {
+            return Err(ConfigError::new(
+                ConfigErrorType::Invalid,
+                format!(
+                    "autosave.interval and autosave.debounce can be at most {} seconds",
+                    MAX_SECONDS
+                ),
+            ));
+        }
+
+        if self.autosave.keep == 0 {
+            return Err(ConfigError::new(
+                ConfigErrorType::Invalid,
+                "autosave.keep must be at least one".to_string(),
+            ));
+        }
+
+        for rule in self.exclude.iter() {
+            if rule.cmdline.is_none() && rule.window_name.is_none() {
+                return Err(ConfigError::new(
+                    ConfigErrorType::Invalid,
+                    "exclude rules need a cmdline or window_name pattern".to_string(),
+                ));
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn session_directory(self: &Self) -> PathBuf {
+        match self.session_directory.as_ref() {
+            Some(dir) => return xdg::expand_home(dir),
+            None => return xdg::state_directory(),
+        }
+    }
+
+    pub fn restore_timeout(self: &Self) -> Duration {
+        return Duration::from_secs(self.restore.timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_defaults() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut config = Config::default();
+        config.restore.timeout = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.exclude.push(ExcludeRule::default());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<Config>("[restore]\ntimeout = 5\n").is_ok());
+        assert!(toml::from_str::<Config>("[restore]\ntimout = 5\n").is_err());
+    }
+
+    #[test]
+    fn keeps_at_least_one_autosave() {
+        let mut config = Config::default();
+        config.autosave.keep = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn bounds_autosave_durations() {
+        let mut config = Config::default();
+        config.autosave.interval = MAX_SECONDS;
+        config.autosave.debounce = MAX_SECONDS;
+        assert!(config.validate().is_ok());
+
+        config.autosave.interval = u64::MAX;
+        assert!(config.validate().is_err());
+
+        config.autosave.interval = 0;
+        config.autosave.debounce = MAX_SECONDS + 1;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn bounds_the_restore_timeout() {
+        let mut config = Config::default();
+        config.restore.timeout = MAX_SECONDS;
+        assert!(config.validate().is_ok());
+
+        config.restore.timeout = u64::MAX;
+        assert!(config.validate().is_err());
+    }
+}
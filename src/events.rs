@@ -0,0 +1,88 @@
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::info::Desktop;
+use crate::logging;
+
+/// Changes to tracked windows that control socket subscribers and hook commands are told about.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WindowEvent {
+    WindowMoved {
+        window_xid: u32,
+        window_name: String,
+        process_id: Option<usize>,
+        from: Desktop,
+        to: Desktop,
+        desktop_name: String,
+    },
+    /// The process has no tracked windows left, which usually means the application was quit.
+    LastWindowClosed {
+        process_id: Option<usize>,
+        cmdline: String,
+    },
+}
+
+impl WindowEvent {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            WindowEvent::WindowMoved { .. } => return "window_moved",
+            WindowEvent::LastWindowClosed { .. } => return "last_window_closed",
+        }
+    }
+}
+
+/// Hands every published event to all current subscribers. Subscribers that went away are
+/// dropped the next time an event is published.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<WindowEvent>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        return EventBus {
+            subscribers: Mutex::new(Vec::new()),
+        };
+    }
+
+    pub fn subscribe(self: &Self) -> Receiver<WindowEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        return receiver;
+    }
+
+    pub fn publish(self: &Self, event: &WindowEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/// Runs a hook command through the shell without waiting for it. The event is passed as JSON in
+/// `SESSIOND_EVENT_JSON` and its name in `SESSIOND_EVENT`.
+pub fn run_hook(command: &str, event: &WindowEvent) {
+    let mut child = match Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("SESSIOND_EVENT", event.name())
+        .env("SESSIOND_EVENT_JSON", serde_json::to_string(event).unwrap())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            logging::warn(&format!("running {} hook: {}", event.name(), err));
+            return;
+        }
+    };
+
+    thread::spawn(move || child.wait());
+}
@@ -1,114 +1,625 @@
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsStr;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::string::String;
 
-use sysinfo::{ProcessesToUpdate, System};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
 
 use crate::x11_client::X11WindowInformation;
 
-#[derive(PartialEq, Eq, Hash)]
+/// Argument vector of a running process, `None` once it is gone. Refreshing single processes
+/// never drops dead ones from a `System`, so every lookup starts from an empty one.
+pub fn process_command(pid: Pid) -> Option<Vec<String>> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        ProcessRefreshKind::new().with_cmd(UpdateKind::OnlyIfNotSet),
+    );
+    return Some(
+        system
+            .process(pid)?
+            .cmd()
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+    );
+}
+
+/// Position of the window frame's top-left corner in root coordinates together with the size of
+/// the client area, which is what _NET_MOVERESIZE_WINDOW expects with north-west gravity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// _NET_WM_DESKTOP value of windows shown on every desktop.
+const ALL_DESKTOPS: u32 = 0xFFFFFFFF;
+
+/// The desktop a window is on. Serialized as the desktop number, or as `"all"` or `"unknown"`,
+/// which keeps the number format older snapshots used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(from = "DesktopRepr", into = "DesktopRepr")]
+pub enum Desktop {
+    Number(u32),
+    /// Sticky windows, shown on all desktops.
+    All,
+    /// The window manager hasn't placed the window on a desktop (yet).
+    Unknown,
+}
+
+impl From<u32> for Desktop {
+    fn from(number: u32) -> Self {
+        if number == ALL_DESKTOPS {
+            return Desktop::All;
+        }
+        return Desktop::Number(number);
+    }
+}
+
+impl Desktop {
+    /// The value to set _NET_WM_DESKTOP to, `None` when the window manager should decide.
+    pub fn to_net_wm_desktop(self: &Self) -> Option<u32> {
+        match self {
+            Desktop::Number(number) => return Some(*number),
+            Desktop::All => return Some(ALL_DESKTOPS),
+            Desktop::Unknown => return None,
+        }
+    }
+}
+
+impl fmt::Display for Desktop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Desktop::Number(number) => return write!(f, "{}", number),
+            Desktop::All => return write!(f, "all"),
+            Desktop::Unknown => return write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum DesktopRepr {
+    Number(u32),
+    Special(SpecialDesktop),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SpecialDesktop {
+    All,
+    Unknown,
+}
+
+impl From<DesktopRepr> for Desktop {
+    fn from(repr: DesktopRepr) -> Self {
+        match repr {
+            DesktopRepr::Number(number) => return Desktop::from(number),
+            DesktopRepr::Special(SpecialDesktop::All) => return Desktop::All,
+            DesktopRepr::Special(SpecialDesktop::Unknown) => return Desktop::Unknown,
+        }
+    }
+}
+
+impl From<Desktop> for DesktopRepr {
+    fn from(desktop: Desktop) -> Self {
+        match desktop {
+            Desktop::Number(number) => return DesktopRepr::Number(number),
+            Desktop::All => return DesktopRepr::Special(SpecialDesktop::All),
+            Desktop::Unknown => return DesktopRepr::Special(SpecialDesktop::Unknown),
+        }
+    }
+}
+
+/// The X display a session was captured on and the desktops its window manager offered, in
+/// desktop number order.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplayLayout {
+    pub display: String,
+    pub screen: i32,
+    pub desktops: Vec<String>,
+    /// Missing in snapshots written before the current desktop was recorded.
+    #[serde(default)]
+    pub current_desktop: Option<u32>,
+}
+
+impl DisplayLayout {
+    /// Only specific desktops have names, and not every window manager names all of them.
+    pub fn desktop_name(self: &Self, desktop: Desktop) -> String {
+        match desktop {
+            Desktop::Number(number) => {
+                return self
+                    .desktops
+                    .get(number as usize)
+                    .cloned()
+                    .unwrap_or_default()
+            }
+            Desktop::All | Desktop::Unknown => return String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowState {
+    MaximizedVert,
+    MaximizedHorz,
+    Fullscreen,
+    Sticky,
+    Above,
+    Below,
+    Hidden,
+    Shaded,
+}
+
+impl WindowState {
+    pub const ALL: [WindowState; 8] = [
+        WindowState::MaximizedVert,
+        WindowState::MaximizedHorz,
+        WindowState::Fullscreen,
+        WindowState::Sticky,
+        WindowState::Above,
+        WindowState::Below,
+        WindowState::Hidden,
+        WindowState::Shaded,
+    ];
+}
+
+/// _NET_WM_WINDOW_TYPE, reduced to the first type the daemon knows about.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowType {
+    Desktop,
+    Dock,
+    Toolbar,
+    Menu,
+    Utility,
+    Splash,
+    Dialog,
+    DropdownMenu,
+    PopupMenu,
+    Tooltip,
+    Notification,
+    Combo,
+    Dnd,
+    #[default]
+    Normal,
+}
+
+impl WindowType {
+    pub const ALL: [WindowType; 14] = [
+        WindowType::Desktop,
+        WindowType::Dock,
+        WindowType::Toolbar,
+        WindowType::Menu,
+        WindowType::Utility,
+        WindowType::Splash,
+        WindowType::Dialog,
+        WindowType::DropdownMenu,
+        WindowType::PopupMenu,
+        WindowType::Tooltip,
+        WindowType::Notification,
+        WindowType::Combo,
+        WindowType::Dnd,
+        WindowType::Normal,
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WmClass {
+    pub instance: String,
+    pub class: String,
+}
+
+/// Properties ICCCM clients set to identify themselves. Unlike the title they stay the same for
+/// the lifetime of a window and across relaunches, which makes them the keys for matching
+/// restored windows. Each one is `None` when the client didn't set it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IcccmProperties {
+    pub wm_class: Option<WmClass>,
+    pub wm_command: Option<Vec<String>>,
+    pub client_machine: Option<String>,
+    pub window_role: Option<String>,
+}
+
+const MAX_TITLE_HISTORY: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TitleChange {
+    pub timestamp: DateTime<Local>,
+    pub title: String,
+}
+
+#[derive(PartialEq, Eq)]
 pub struct WindowInfo {
     pub window_name: String,
     pub window_xid: u32,
     pub desktop_name: String,
-    pub desktop_number: u32,
+    pub desktop: Desktop,
+    pub geometry: WindowGeometry,
+    pub states: BTreeSet<WindowState>,
+    pub icccm: IcccmProperties,
+    pub window_type: WindowType,
+    /// WM_TRANSIENT_FOR, the window a dialog or similar belongs to.
+    pub transient_for: Option<u32>,
+    /// The most recent titles, oldest first, ending with `window_name`.
+    pub title_history: VecDeque<TitleChange>,
 }
 
 impl WindowInfo {
-    pub fn new(name: &String, xid: u32, dname: &String, dnum: u32) -> Self {
+    pub fn set_title(self: &mut Self, title: String) {
+        if title == self.window_name {
+            return;
+        }
+
+        if self.title_history.len() == MAX_TITLE_HISTORY {
+            self.title_history.pop_front();
+        }
+        self.title_history.push_back(TitleChange {
+            timestamp: Local::now(),
+            title: title.clone(),
+        });
+        self.window_name = title;
+    }
+}
+
+impl From<&X11WindowInformation<'_>> for WindowInfo {
+    fn from(x11_window_info: &X11WindowInformation) -> Self {
         return WindowInfo {
-            window_name: name.clone(),
-            window_xid: xid,
-            desktop_name: dname.clone(),
-            desktop_number: dnum,
+            window_name: x11_window_info.x11_window_name.clone(),
+            window_xid: x11_window_info.x11_resource_id,
+            desktop_name: x11_window_info.x11_desktop_name.clone(),
+            desktop: x11_window_info.x11_desktop,
+            geometry: x11_window_info.x11_geometry,
+            states: x11_window_info.x11_states.clone(),
+            icccm: x11_window_info.x11_icccm.clone(),
+            window_type: x11_window_info.x11_window_type,
+            transient_for: x11_window_info.x11_transient_for,
+            title_history: VecDeque::from([TitleChange {
+                timestamp: Local::now(),
+                title: x11_window_info.x11_window_name.clone(),
+            }]),
         };
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+/// Start time of a process in clock ticks after boot, field 22 of /proc/<pid>/stat. Together with
+/// the PID it identifies a process even after the PID has been reused.
+pub fn process_start_time(process_id: usize) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", process_id)).ok()?;
+    return parse_start_time(&stat);
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name in field 2 is in parentheses and may itself contain spaces and
+    // parentheses, so fields are counted from the last closing one, which ends field 2.
+    let fields = &stat[stat.rfind(')')? + 1..];
+    return fields.split_whitespace().nth(19)?.parse().ok();
+}
+
+/// A process is identified by its PID and start time; the command line is only recorded once
+/// when the process is first seen, so it doesn't matter if it changes or the process exits
+/// before its windows are gone. Windows whose owner couldn't be determined are all kept under
+/// one entry without a process id and an empty command line.
+#[derive(Clone)]
 pub struct ProcessInfo {
+    /// The arguments joined with spaces, for display and matching.
     pub cmdline: String,
-    pub process_id: usize,
+    /// The arguments as the process got them, to relaunch it with.
+    pub command: Vec<String>,
+    pub process_id: Option<usize>,
+    pub start_time: Option<u64>,
 }
 
 impl ProcessInfo {
-    pub fn new(cmdline: String, process_id: usize) -> Self {
+    pub fn new(command: Vec<String>, process_id: Option<usize>, start_time: Option<u64>) -> Self {
         return ProcessInfo {
-            cmdline,
+            cmdline: command.join(" "),
+            command,
             process_id,
+            start_time,
         };
     }
 }
 
+impl PartialEq for ProcessInfo {
+    fn eq(&self, other: &Self) -> bool {
+        return self.process_id == other.process_id && self.start_time == other.start_time;
+    }
+}
+
+impl Eq for ProcessInfo {}
+
+impl Hash for ProcessInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.process_id.hash(state);
+        self.start_time.hash(state);
+    }
+}
+
+/// What happened to the owner of a window that was removed from the model.
+pub struct RemovedWindow {
+    pub process: ProcessInfo,
+    /// The process has no tracked windows left and was dropped from the model.
+    pub last_window: bool,
+}
+
+const MAX_CACHED_PROCESSES: usize = 4096;
+
 pub struct ProcessesWindowsInfo {
-    pub procinfo: HashMap<ProcessInfo, HashSet<WindowInfo>>,
-    sysinfo: System,
+    pub procinfo: HashMap<ProcessInfo, HashMap<u32, WindowInfo>>,
+    // Owner of every tracked window, so windows can be looked up by xid alone, which is all
+    // that is left to go by once a window has been destroyed.
+    window_owners: HashMap<u32, ProcessInfo>,
+    process_cache: HashSet<ProcessInfo>,
+}
+
+impl Default for ProcessesWindowsInfo {
+    fn default() -> Self {
+        return Self::new();
+    }
 }
 
 impl ProcessesWindowsInfo {
     pub fn new() -> Self {
         return ProcessesWindowsInfo {
             procinfo: HashMap::new(),
-            sysinfo: System::new_all(),
+            window_owners: HashMap::new(),
+            process_cache: HashSet::new(),
         };
     }
 
-    pub fn insert(self: &mut Self, x11_window_info: &X11WindowInformation) -> () {
-        self.sysinfo.refresh_processes(ProcessesToUpdate::All);
-        let process = self.sysinfo.process(x11_window_info.process_id);
-        match process {
-            Some(p) => {
-                let cmdline = p.cmd().join(OsStr::new(" ")).into_string().unwrap();
-
-                let window_info = WindowInfo::new(
-                    &x11_window_info.x11_window_name,
-                    x11_window_info.x11_resource_id,
-                    &x11_window_info.x11_desktop_name,
-                    x11_window_info.x11_desktop_number,
-                );
-                let proc_info = ProcessInfo::new(
-                    cmdline,
-                    usize::try_from(x11_window_info.process_id.as_u32()).unwrap(),
-                );
-                match self.procinfo.get_mut(&proc_info) {
-                    Some(w_infos) => {
-                        w_infos.insert(window_info);
-                    }
-                    None => {
-                        let mut hash_set = HashSet::new();
-                        hash_set.insert(window_info);
-                        self.procinfo.insert(proc_info, hash_set);
-                    }
-                }
-            }
+    pub fn window_count(self: &Self) -> usize {
+        return self.procinfo.values().map(|windows| windows.len()).sum();
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.procinfo.clear();
+        self.window_owners.clear();
+    }
+
+    pub fn insert(self: &mut Self, x11_window_info: &X11WindowInformation) {
+        self.remove_window(x11_window_info.x11_resource_id);
+
+        let proc_info = self.lookup_process(x11_window_info.process_id);
+        let window_info = WindowInfo::from(x11_window_info);
+        self.window_owners
+            .insert(window_info.window_xid, proc_info.clone());
+        self.procinfo
+            .entry(proc_info)
+            .or_default()
+            .insert(window_info.window_xid, window_info);
+    }
+
+    // Only the owning process is refreshed, walking all of /proc for every new window is what
+    // makes window storms slow on machines with many processes. Processes are remembered after
+    // their windows are gone, since applications tend to open windows again.
+    fn lookup_process(self: &mut Self, pid: Option<Pid>) -> ProcessInfo {
+        let process_id = pid.map(|pid| pid.as_u32() as usize);
+        let start_time = process_id.and_then(process_start_time);
+        let proc_info = ProcessInfo::new(Vec::new(), process_id, start_time);
+
+        let pid = match pid {
+            Some(pid) => pid,
+            None => return proc_info,
+        };
+        match self.procinfo.get_key_value(&proc_info) {
+            Some((known, _)) => return known.clone(),
+            None => {}
+        }
+        match self.process_cache.get(&proc_info) {
+            Some(known) => return known.clone(),
             None => {}
         }
+
+        // A process that is already gone still leaves its window tracked until it is
+        // destroyed, just without a command line.
+        let proc_info = ProcessInfo::new(
+            process_command(pid).unwrap_or_default(),
+            process_id,
+            start_time,
+        );
+
+        if self.process_cache.len() >= MAX_CACHED_PROCESSES {
+            let procinfo = &self.procinfo;
+            self.process_cache.retain(|p| procinfo.contains_key(p));
+        }
+        self.process_cache.insert(proc_info.clone());
+
+        return proc_info;
     }
 
-    pub fn remove(self: &mut Self, x11_window_info: &X11WindowInformation) -> () {
-        self.sysinfo.refresh_processes(ProcessesToUpdate::All);
-        let process = self.sysinfo.process(x11_window_info.process_id);
+    pub fn remove(self: &mut Self, x11_window_info: &X11WindowInformation) {
+        self.remove_window(x11_window_info.x11_resource_id);
+    }
 
-        match process {
-            Some(p) => {
-                let cmdline = p.cmd().join(OsStr::new(" ")).into_string().unwrap();
-                let pid = usize::try_from(x11_window_info.process_id.as_u32()).unwrap();
-                let proc_info = ProcessInfo::new(cmdline, pid);
+    pub fn remove_window(self: &mut Self, xid: u32) -> Option<RemovedWindow> {
+        let proc_info = self.window_owners.remove(&xid)?;
 
-                match self.procinfo.get_mut(&proc_info) {
-                    Some(windows_of_process) => {
-                        windows_of_process.retain(|window_info| {
-                            window_info.window_xid != x11_window_info.x11_resource_id
-                        });
+        let windows_of_process = self.procinfo.get_mut(&proc_info)?;
+        windows_of_process.remove(&xid);
 
-                        if windows_of_process.is_empty() {
-                            self.procinfo.remove(&proc_info);
-                        }
-                    }
-                    None => {}
-                }
-            }
-            None => {}
+        let last_window = windows_of_process.is_empty();
+        if last_window {
+            self.procinfo.remove(&proc_info);
         }
+
+        return Some(RemovedWindow {
+            process: proc_info,
+            last_window,
+        });
+    }
+
+    pub fn window_owner(self: &Self, xid: u32) -> Option<&ProcessInfo> {
+        return self.window_owners.get(&xid);
+    }
+
+    /// Brings the desktop names stored with every window in line with the window manager's
+    /// current ones.
+    pub fn rename_desktops(self: &mut Self, layout: &DisplayLayout) {
+        for window_info in self
+            .procinfo
+            .values_mut()
+            .flat_map(|windows| windows.values_mut())
+        {
+            window_info.desktop_name = layout.desktop_name(window_info.desktop);
+        }
+    }
+
+    /// Changes the record of a tracked window in place, returning what `update` returns or
+    /// `None` when the window isn't tracked.
+    pub fn update_window<F, R>(self: &mut Self, xid: u32, update: F) -> Option<R>
+    where
+        F: FnOnce(&mut WindowInfo) -> R,
+    {
+        let owner = self.window_owners.get(&xid)?;
+        let window_info = self.procinfo.get_mut(owner)?.get_mut(&xid)?;
+        return Some(update(window_info));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sysinfo::Pid;
+    use xcb::x::Window as X11Window;
+    use xcb::{Xid, XidNew};
+
+    fn x11_window(xid: u32) -> X11Window {
+        // `XidNew::new` is only unsafe in older xcb releases.
+        #[allow(unused_unsafe)]
+        return unsafe { X11Window::new(xid) };
+    }
+
+    fn window_information(window: &X11Window, owner: Option<Pid>) -> X11WindowInformation<'_> {
+        return X11WindowInformation {
+            x11_window: window,
+            x11_resource_id: window.resource_id(),
+            x11_window_name: "window".to_string(),
+            x11_desktop: Desktop::Number(0),
+            x11_desktop_name: "main".to_string(),
+            x11_geometry: WindowGeometry::default(),
+            x11_states: BTreeSet::new(),
+            x11_icccm: IcccmProperties::default(),
+            x11_window_type: WindowType::Normal,
+            x11_transient_for: None,
+            process_id: owner,
+        };
+    }
+
+    #[test]
+    fn desktop_serializes_as_number_or_name() {
+        assert_eq!(serde_json::to_string(&Desktop::Number(3)).unwrap(), "3");
+        assert_eq!(serde_json::to_string(&Desktop::All).unwrap(), "\"all\"");
+        assert_eq!(
+            serde_json::to_string(&Desktop::Unknown).unwrap(),
+            "\"unknown\""
+        );
+
+        for desktop in [Desktop::Number(0), Desktop::All, Desktop::Unknown] {
+            let json = serde_json::to_string(&desktop).unwrap();
+            assert_eq!(serde_json::from_str::<Desktop>(&json).unwrap(), desktop);
+        }
+    }
+
+    #[test]
+    fn desktop_reads_the_sticky_number_as_all() {
+        assert_eq!(
+            serde_json::from_str::<Desktop>("4294967295").unwrap(),
+            Desktop::All
+        );
+        assert!(serde_json::from_str::<Desktop>("\"elsewhere\"").is_err());
+    }
+
+    #[test]
+    fn parses_the_start_time_from_stat() {
+        let stat = "1234 (a) b (c)) S 1 1234 1234 0 -1 4194560 100 0 0 0 5 3 0 0 20 0 1 0 \
+                    987654 10000 200 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("1234 (truncated) S 1"), None);
+        assert_eq!(parse_start_time(""), None);
+    }
+
+    #[test]
+    fn reads_the_start_time_of_this_process() {
+        assert!(process_start_time(std::process::id() as usize).is_some());
+    }
+
+    #[test]
+    fn reports_the_last_window_of_a_process() {
+        let owner = Some(Pid::from_u32(std::process::id()));
+        let windows = [x11_window(0x100), x11_window(0x200)];
+        let mut model = ProcessesWindowsInfo::new();
+        for window in windows.iter() {
+            model.insert(&window_information(window, owner));
+        }
+        assert_eq!(model.procinfo.len(), 1);
+        assert_eq!(model.window_count(), 2);
+
+        let removed = model.remove_window(0x100).unwrap();
+        assert!(!removed.last_window);
+        assert_eq!(
+            removed.process.process_id,
+            Some(std::process::id() as usize)
+        );
+        assert_eq!(model.window_count(), 1);
+
+        let removed = model.remove_window(0x200).unwrap();
+        assert!(removed.last_window);
+        assert!(model.procinfo.is_empty());
+    }
+
+    #[test]
+    fn ignores_unknown_windows() {
+        let window = x11_window(0x100);
+        let mut model = ProcessesWindowsInfo::new();
+        model.insert(&window_information(&window, None));
+
+        assert!(model.remove_window(0x300).is_none());
+        assert_eq!(model.window_count(), 1);
+
+        assert!(model.remove_window(0x100).unwrap().last_window);
+        assert!(model.remove_window(0x100).is_none());
+    }
+
+    #[test]
+    fn records_title_changes() {
+        let window = x11_window(0x100);
+        let mut window_info = WindowInfo::from(&window_information(&window, None));
+        assert_eq!(window_info.title_history.len(), 1);
+
+        window_info.set_title("window".to_string());
+        assert_eq!(window_info.title_history.len(), 1);
+
+        window_info.set_title("renamed".to_string());
+        assert_eq!(window_info.window_name, "renamed");
+        assert_eq!(window_info.title_history.len(), 2);
+        assert_eq!(
+            window_info.title_history.back().unwrap().title,
+            window_info.window_name
+        );
+    }
+
+    #[test]
+    fn drops_the_oldest_titles() {
+        let window = x11_window(0x100);
+        let mut window_info = WindowInfo::from(&window_information(&window, None));
+        for index in 1..=MAX_TITLE_HISTORY {
+            window_info.set_title(format!("title {}", index));
+        }
+
+        assert_eq!(window_info.title_history.len(), MAX_TITLE_HISTORY);
+        assert_eq!(window_info.title_history.front().unwrap().title, "title 1");
+        assert_eq!(
+            window_info.title_history.back().unwrap().title,
+            window_info.window_name
+        );
     }
 }
@@ -0,0 +1,438 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::events::WindowEvent;
+use crate::logging;
+use crate::restore::RestoreEngine;
+use crate::session::{ProcessSnapshot, SessionError, SessionSnapshot, SessionSummary};
+use crate::state::DaemonState;
+use crate::x11_client::X11Client;
+use crate::xdg;
+
+const SOCKET_NAME: &str = "control.sock";
+
+#[derive(Debug)]
+pub enum IpcErrorType {
+    Io,
+    Protocol,
+    AlreadyRunning,
+}
+
+#[derive(Debug)]
+pub struct IpcError {
+    pub kind: IpcErrorType,
+    pub message: String,
+}
+
+impl IpcError {
+    pub fn new(kind: IpcErrorType, message: String) -> Self {
+        return IpcError { kind, message };
+    }
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:?}: {}", self.kind, self.message);
+    }
+}
+
+impl From<io::Error> for IpcError {
+    fn from(err: io::Error) -> Self {
+        return IpcError::new(IpcErrorType::Io, err.to_string());
+    }
+}
+
+impl From<serde_json::Error> for IpcError {
+    fn from(err: serde_json::Error) -> Self {
+        return IpcError::new(IpcErrorType::Protocol, err.to_string());
+    }
+}
+
+/// Requests and responses are exchanged as one JSON document per line, so a connection can carry
+/// several requests and shell scripts can talk to the daemon with `socat` or `nc -U`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    List,
+    Save {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    Restore {
+        #[serde(default)]
+        name: Option<String>,
+        /// Restores the autosave this many saves back instead of a saved session.
+        #[serde(default)]
+        autosave: Option<usize>,
+    },
+    Sessions,
+    Rename {
+        from: String,
+        to: String,
+    },
+    Delete {
+        name: String,
+    },
+    Status,
+    /// Turns the connection into a stream of `WindowEvent`s, one per line, after the
+    /// `subscribed` response.
+    Subscribe,
+    Shutdown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started: DateTime<Local>,
+    pub uptime_seconds: i64,
+    pub events_processed: u64,
+    pub paused: bool,
+    pub processes: usize,
+    pub windows: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Processes {
+        processes: Vec<ProcessSnapshot>,
+    },
+    Saved {
+        path: PathBuf,
+        processes: usize,
+        windows: usize,
+    },
+    Restored {
+        launched: Vec<String>,
+        failed: Vec<String>,
+        placed: usize,
+        unmatched: usize,
+        #[serde(default)]
+        skipped: usize,
+    },
+    Sessions {
+        sessions: Vec<SessionSummary>,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+    Deleted {
+        name: String,
+    },
+    Status(DaemonStatus),
+    Subscribed,
+    ShuttingDown,
+    Error {
+        message: String,
+    },
+}
+
+impl From<SessionError> for Response {
+    fn from(err: SessionError) -> Self {
+        return Response::Error {
+            message: err.to_string(),
+        };
+    }
+}
+
+pub fn socket_path() -> PathBuf {
+    return xdg::runtime_directory().join(SOCKET_NAME);
+}
+
+/// Sends a single request to the running daemon and waits for its response.
+pub fn send_request(request: &Request) -> Result<Response, IpcError> {
+    let mut stream = UnixStream::connect(socket_path())?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    if reply.is_empty() {
+        return Err(IpcError::new(
+            IpcErrorType::Protocol,
+            "connection closed without a response".to_string(),
+        ));
+    }
+
+    return Ok(serde_json::from_str(&reply)?);
+}
+
+/// Subscribes to the daemon's window events and hands each one to `handle` until the daemon
+/// goes away.
+pub fn subscribe<F>(mut handle: F) -> Result<(), IpcError>
+where
+    F: FnMut(WindowEvent),
+{
+    let mut stream = UnixStream::connect(socket_path())?;
+
+    let mut line = serde_json::to_string(&Request::Subscribe)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut lines = BufReader::new(stream).lines();
+    match lines.next() {
+        Some(reply) => match serde_json::from_str::<Response>(&reply?)? {
+            Response::Subscribed => {}
+            Response::Error { message } => {
+                return Err(IpcError::new(IpcErrorType::Protocol, message))
+            }
+            _ => {
+                return Err(IpcError::new(
+                    IpcErrorType::Protocol,
+                    "unexpected response to subscribe".to_string(),
+                ))
+            }
+        },
+        None => {
+            return Err(IpcError::new(
+                IpcErrorType::Protocol,
+                "connection closed without a response".to_string(),
+            ))
+        }
+    }
+
+    for line in lines {
+        handle(serde_json::from_str(&line?)?);
+    }
+
+    return Ok(());
+}
+
+#[derive(Clone)]
+struct IpcContext {
+    state: DaemonState,
+}
+
+pub struct IpcServer {
+    listener: UnixListener,
+    path: PathBuf,
+    context: IpcContext,
+}
+
+impl IpcServer {
+    pub fn bind(state: DaemonState) -> Result<Self, IpcError> {
+        let path = socket_path();
+        if let Some(parent) = path.parent() {
+            xdg::create_runtime_directory(parent)?;
+        }
+
+        // A socket file nobody answers on is left over from a daemon that didn't shut down
+        // cleanly and can be replaced.
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(IpcError::new(
+                    IpcErrorType::AlreadyRunning,
+                    format!("another daemon is listening on {}", path.display()),
+                ));
+            }
+            fs::remove_file(&path)?;
+        }
+
+        return Ok(IpcServer {
+            listener: UnixListener::bind(&path)?,
+            path,
+            context: IpcContext { state },
+        });
+    }
+
+    pub fn path(self: &Self) -> &Path {
+        return &self.path;
+    }
+
+    pub fn spawn(self: Self) -> thread::JoinHandle<()> {
+        return thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let context = self.context.clone();
+                        thread::spawn(move || context.serve(stream));
+                    }
+                    Err(err) => logging::warn(&format!("accepting IPC connection: {}", err)),
+                }
+            }
+        });
+    }
+}
+
+impl IpcContext {
+    fn serve(self: &Self, stream: UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(Request::Subscribe) => return self.stream_events(&mut writer),
+                Ok(request) => self.handle(request),
+                Err(err) => Response::Error {
+                    message: format!("malformed request: {}", err),
+                },
+            };
+
+            let mut reply = serde_json::to_string(&response).unwrap();
+            reply.push('\n');
+            if writer.write_all(reply.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Subscribers only listen from here on, the connection ends when writing to it fails.
+    fn stream_events(self: &Self, writer: &mut UnixStream) {
+        let events = self.state.events.subscribe();
+
+        let mut reply = serde_json::to_string(&Response::Subscribed).unwrap();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+
+        for event in events.iter() {
+            let mut line = serde_json::to_string(&event).unwrap();
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle(self: &Self, request: Request) -> Response {
+        match request {
+            Request::List => {
+                let snapshot = SessionSnapshot::from(&*self.state.proc_win_info.lock().unwrap());
+                return Response::Processes {
+                    processes: snapshot.processes,
+                };
+            }
+            Request::Save { name } => return self.save(name.as_deref()),
+            Request::Restore { name, autosave } => return self.restore(name.as_deref(), autosave),
+            Request::Sessions => {
+                let directory = self.state.config.lock().unwrap().session_directory();
+                match SessionSnapshot::list_named(&directory) {
+                    Ok(sessions) => return Response::Sessions { sessions },
+                    Err(err) => return Response::from(err),
+                }
+            }
+            Request::Rename { from, to } => {
+                let directory = self.state.config.lock().unwrap().session_directory();
+                match SessionSnapshot::rename_named(&directory, &from, &to) {
+                    Ok(_) => return Response::Renamed { from, to },
+                    Err(err) => return Response::from(err),
+                }
+            }
+            Request::Delete { name } => {
+                let directory = self.state.config.lock().unwrap().session_directory();
+                match SessionSnapshot::delete_named(&directory, &name) {
+                    Ok(_) => return Response::Deleted { name },
+                    Err(err) => return Response::from(err),
+                }
+            }
+            Request::Status => {
+                let statistics = self.state.statistics.lock().unwrap();
+                let proc_win_info = self.state.proc_win_info.lock().unwrap();
+                return Response::Status(DaemonStatus {
+                    pid: std::process::id(),
+                    started: statistics.started,
+                    uptime_seconds: statistics.uptime_seconds(),
+                    events_processed: statistics.events_processed,
+                    paused: *self.state.is_paused.lock().unwrap(),
+                    processes: proc_win_info.procinfo.len(),
+                    windows: proc_win_info.window_count(),
+                });
+            }
+            Request::Subscribe => return Response::Subscribed,
+            Request::Shutdown => {
+                self.state.stop();
+                return Response::ShuttingDown;
+            }
+        }
+    }
+
+    fn save(self: &Self, name: Option<&str>) -> Response {
+        let directory = self.state.config.lock().unwrap().session_directory();
+        let path = match SessionSnapshot::path_for(&directory, name) {
+            Ok(path) => path,
+            Err(err) => return Response::from(err),
+        };
+
+        let snapshot = self.state.snapshot();
+        match snapshot.save(&path) {
+            Ok(_) => {
+                return Response::Saved {
+                    path,
+                    processes: snapshot.processes.len(),
+                    windows: snapshot.window_count(),
+                }
+            }
+            Err(err) => return Response::from(err),
+        }
+    }
+
+    // Restoring polls the X server for new windows for a while, so it runs on its own connection
+    // instead of competing with the event loop for the daemon's one.
+    fn restore(self: &Self, name: Option<&str>, autosave: Option<usize>) -> Response {
+        let config = self.state.config.lock().unwrap().clone();
+        let path = match autosave {
+            Some(index) => Ok(SessionSnapshot::autosave_path(
+                &config.session_directory(),
+                index,
+            )),
+            None => SessionSnapshot::path_for(&config.session_directory(), name),
+        };
+        let mut snapshot = match path.and_then(|p| SessionSnapshot::load(&p)) {
+            Ok(snapshot) => snapshot,
+            Err(err) => return Response::from(err),
+        };
+        snapshot.exclude(&config.exclude);
+
+        let mut x11_client = match X11Client::new() {
+            Ok(x11_client) => x11_client,
+            Err(err) => {
+                return Response::Error {
+                    message: err.to_string(),
+                }
+            }
+        };
+        let x11_client = match x11_client.connect() {
+            Ok(x11_client) => x11_client,
+            Err(err) => {
+                return Response::Error {
+                    message: err.to_string(),
+                }
+            }
+        };
+        let mut engine = RestoreEngine::new(
+            x11_client,
+            config.restore_timeout(),
+            config.restore.desktops,
+        );
+        let report = engine.restore(&snapshot);
+
+        return Response::Restored {
+            launched: report.launched,
+            failed: report.failed,
+            placed: report.placed,
+            unmatched: report.unmatched,
+            skipped: report.skipped,
+        };
+    }
+}
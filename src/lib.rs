@@ -1,3 +1,19 @@
+#![allow(
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type,
+    clippy::single_match
+)]
+
 pub mod application;
+pub mod cli;
+pub mod config;
+pub mod events;
 pub mod info;
+pub mod ipc;
+pub mod logging;
+pub mod restore;
+pub mod session;
+pub mod state;
+pub mod wakeup;
 pub mod x11_client;
+pub mod xdg;
@@ -0,0 +1,83 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::config::LoggingConfig;
+use crate::xdg;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+struct Logger {
+    level: LogLevel,
+    file: Option<File>,
+}
+
+// Until `init` runs everything at info level and above goes to stderr.
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: LogLevel::Info,
+    file: None,
+});
+
+pub fn init(config: &LoggingConfig) -> io::Result<()> {
+    let file = match config.file.as_ref() {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(xdg::expand_home(path))?,
+        ),
+        None => None,
+    };
+
+    let mut logger = LOGGER.lock().unwrap();
+    logger.level = config.level;
+    logger.file = file;
+
+    return Ok(());
+}
+
+pub fn log(level: LogLevel, message: &str) {
+    let mut logger = LOGGER.lock().unwrap();
+    if level > logger.level {
+        return;
+    }
+
+    let line = format!(
+        "{} {:<5} {}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+        format!("{:?}", level).to_uppercase(),
+        message
+    );
+    let _ = match logger.file.as_mut() {
+        Some(file) => file.write_all(line.as_bytes()),
+        None => io::stderr().write_all(line.as_bytes()),
+    };
+}
+
+pub fn error(message: &str) {
+    log(LogLevel::Error, message);
+}
+
+pub fn warn(message: &str) {
+    log(LogLevel::Warn, message);
+}
+
+pub fn info(message: &str) {
+    log(LogLevel::Info, message);
+}
+
+pub fn debug(message: &str) {
+    log(LogLevel::Debug, message);
+}
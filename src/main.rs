@@ -1,3 +1,9 @@
+#![allow(
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type,
+    clippy::single_match
+)]
+
 use std::clone::Clone;
 use std::env;
 use std::fs::File;
@@ -7,7 +13,6 @@ use std::process;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 
-use libc;
 use nix::errno::Errno;
 use nix::fcntl::{open, OFlag as FileOFlag};
 use nix::sys::stat::{umask, Mode as FileMode};
@@ -16,11 +21,16 @@ use signal_hook::consts::signal::*;
 use signal_hook::consts::TERM_SIGNALS;
 use signal_hook::flag;
 use signal_hook::iterator::exfiltrator::origin::WithOrigin;
-use signal_hook::iterator::SignalsInfo;
+use signal_hook::iterator::{Handle, SignalsInfo};
 
-use sessiond::application::{Application, ApplicationError};
+use sessiond::application::{Application, ApplicationError, ApplicationErrorType};
+use sessiond::cli::{self, Cli, Command};
+use sessiond::config::Config;
+use sessiond::logging;
+use sessiond::state::DaemonState;
 use sessiond::x11_client::X11Client;
 
+#[derive(Debug)]
 enum DaemonizeErrorType {
     Fork,
     NewSession,
@@ -76,11 +86,11 @@ struct Daemon {
 }
 
 impl Daemon {
-    fn new(is_running: Arc<Mutex<bool>>) -> Self {
+    fn new(is_running: Arc<Mutex<bool>>, stderr: Stdio) -> Self {
         return Daemon {
             stdin: Stdio::devnull(),
             stdout: Stdio::devnull(),
-            stderr: Stdio::devnull(),
+            stderr,
 
             is_running,
         };
@@ -89,9 +99,7 @@ impl Daemon {
     fn daemonize(self: &Self) -> Result<ForkResult, DaemonizeError> {
         let pid = unsafe { fork() };
         match pid {
-            Ok(ForkResult::Child) => {
-                ();
-            }
+            Ok(ForkResult::Child) => {}
 
             Ok(ForkResult::Parent { .. }) => {
                 return Ok(pid.unwrap());
@@ -105,7 +113,9 @@ impl Daemon {
             }
         }
 
-        umask(FileMode::empty());
+        // Snapshots hold commands that get run on restore, so nothing the daemon creates may be
+        // writable, or readable, by anyone else.
+        umask(FileMode::from_bits_truncate(0o077));
 
         match setsid() {
             Ok(_) => (),
@@ -127,10 +137,10 @@ impl Daemon {
             }
         }
 
-        /*match self.redirect_standard_streams() {
+        match self.redirect_standard_streams() {
             Ok(_) => (),
             Err(err) => return Err(err),
-        }*/
+        }
 
         return Ok(pid.unwrap());
     }
@@ -168,7 +178,7 @@ impl Daemon {
                         Ok(res) => return Ok(res),
                         Err(_) => {
                             return Err(DaemonizeError {
-                                error: DaemonizeErrorType::RedirectStream,
+                                error: DaemonizeErrorType::RedirectToFile,
                                 retcode: Errno::last_raw(),
                             })
                         }
@@ -179,7 +189,7 @@ impl Daemon {
         };
 
         process_stdio(libc::STDIN_FILENO, &self.stdin)?;
-        //process_stdio(libc::STDOUT_FILENO, &self.stdout)?;
+        process_stdio(libc::STDOUT_FILENO, &self.stdout)?;
         process_stdio(libc::STDERR_FILENO, &self.stderr)?;
 
         return Ok(());
@@ -190,79 +200,176 @@ impl Daemon {
     }
 }
 
+struct CloseOnDrop(Handle);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
 fn main() -> process::ExitCode {
-    let daemon_is_running = Arc::new(Mutex::new(true));
-    let daemon = Daemon::new(daemon_is_running.clone());
-
-    match daemon.daemonize() {
-        Ok(ForkResult::Child) => {
-            // Make sure double CTRL+C and similar kills
-            let term_now = Arc::new(AtomicBool::new(false));
-            for sig in TERM_SIGNALS {
-                // When terminated by a second term signal, exit with exit code 1.
-                // This will do nothing the first time (because term_now is false).
-                flag::register_conditional_shutdown(*sig, 1, Arc::clone(&term_now)).unwrap();
-                // But this will "arm" the above for the second time, by setting it to true.
-                // The order of registering these is important, if you put this one first, it will
-                // first arm and then terminate ‒ all in the first round.
-                flag::register(*sig, Arc::clone(&term_now)).unwrap();
-            }
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match Cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(message) => {
+            eprintln!("sessiond: {}\n\n{}", message, cli::USAGE);
+            return process::ExitCode::from(2);
+        }
+    };
 
-            // Subscribe to all these signals with information about where they come from. We use the
-            // extra info only for logging in this example (it is not available on all the OSes or at
-            // all the occasions anyway, it may return `Unknown`).
-            let mut sigs = vec![
-                // Some terminal handling
-                SIGTSTP, SIGCONT, SIGWINCH,
-                // Reload of configuration for daemons ‒ um, is this example for a TUI app or a daemon
-                // O:-)? You choose...
-                SIGHUP, // Application-specific action, to print some statistics.
-                SIGUSR1,
-            ];
-            sigs.extend(TERM_SIGNALS);
-            let mut signals = SignalsInfo::<WithOrigin>::new(&sigs).unwrap();
-
-            let daemon_thread = std::thread::spawn(move || {
-                let mut x11_client = X11Client::new();
-                
-                let mut app = Application::new(daemon.is_running.clone(), &mut x11_client);
-                
-                return daemon.run(&mut app);
-            });
-            // This is the actual application that'll start in its own thread. We'll control it from
-            // this thread based on the signals, but it keeps running.
-            // This is called after all the signals got registered, to avoid the short race condition
-            // in the first registration of each signal in multi-threaded programs.
-
-            // Consume all the incoming signals. This happens in "normal" Rust thread, not in the
-            // signal handlers. This means that we are allowed to do whatever we like in here, without
-            // restrictions, but it also means the kernel believes the signal already got delivered, we
-            // handle them in delayed manner. This is in contrast with eg the above
-            // `register_conditional_shutdown` where the shutdown happens *inside* the handler.
-            for info in &mut signals {
-                // Will print info about signal + where it comes from.
-                match info.signal {
-                    term_sig => {
-                        // These are all the ones left
-                        *daemon_is_running.lock().unwrap() = false;
-                        assert!(TERM_SIGNALS.contains(&term_sig));
-                        break;
-                    }
-                }
+    match cli.command {
+        Command::Daemon { foreground } => return run_daemon(foreground),
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return process::ExitCode::SUCCESS;
+        }
+        command => return cli::run_client(&command, cli.json),
+    }
+}
+
+fn load_config(path: &Path) -> Result<Config, String> {
+    let config = Config::load(path).map_err(|err| err.to_string())?;
+    logging::init(&config.logging).map_err(|err| format!("opening log file: {}", err))?;
+    return Ok(config);
+}
+
+fn run_daemon(foreground: bool) -> process::ExitCode {
+    // Configuration problems are reported before forking, while stderr still reaches the user.
+    let config_path = Config::default_path();
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("sessiond: {}", message);
+            return process::ExitCode::FAILURE;
+        }
+    };
+
+    // Without a log file the log goes to stderr, which then has to stay open.
+    let stderr = match config.logging.file {
+        Some(_) => Stdio::devnull(),
+        None => Stdio::keep(),
+    };
+    let daemon = Daemon::new(Arc::new(Mutex::new(true)), stderr);
+    let state = match DaemonState::new(daemon.is_running.clone(), config) {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("sessiond: creating event loop wakeup: {}", err);
+            return process::ExitCode::FAILURE;
+        }
+    };
+
+    if !foreground {
+        match daemon.daemonize() {
+            Ok(ForkResult::Child) => {}
+
+            Ok(ForkResult::Parent { .. }) => {
+                process::exit(0);
             }
 
-            match daemon_thread.join().unwrap() {
-                Ok(_) => process::exit(0),
-                Err(err) => process::exit(err.retcode),
+            Err(err) => {
+                eprintln!("sessiond: cannot daemonize: {:?} failed", err.error);
+                process::exit(-err.retcode);
             }
         }
+    }
 
-        Ok(ForkResult::Parent { .. }) => {
-            process::exit(0);
-        }
+    // Make sure double CTRL+C and similar kills
+    let term_now = Arc::new(AtomicBool::new(false));
+    for sig in TERM_SIGNALS {
+        // When terminated by a second term signal, exit with exit code 1.
+        // This will do nothing the first time (because term_now is false).
+        flag::register_conditional_shutdown(*sig, 1, Arc::clone(&term_now)).unwrap();
+        // But this will "arm" the above for the second time, by setting it to true.
+        // The order of registering these is important, if you put this one first, it will
+        // first arm and then terminate ‒ all in the first round.
+        flag::register(*sig, Arc::clone(&term_now)).unwrap();
+    }
 
-        Err(err) => {
-            process::exit(-err.retcode);
+    // Subscribe to all these signals with information about where they come from. We use the
+    // extra info only for logging in this example (it is not available on all the OSes or at
+    // all the occasions anyway, it may return `Unknown`).
+    let mut sigs = vec![
+        // Pause and resume window tracking; SIGWINCH is accepted and ignored
+        SIGTSTP, SIGCONT, SIGWINCH,
+        // Reload of the configuration file
+        SIGHUP, // Write statistics to the log
+        SIGUSR1,
+    ];
+    sigs.extend(TERM_SIGNALS);
+    let mut signals = SignalsInfo::<WithOrigin>::new(&sigs).unwrap();
+
+    let app_state = state.clone();
+    let signals_handle = signals.handle();
+    let daemon_thread = std::thread::spawn(move || {
+        // However the daemon ends, the signal loop below has to end with it.
+        let _close_signals = CloseOnDrop(signals_handle);
+
+        let mut x11_client = match X11Client::new() {
+            Ok(x11_client) => x11_client,
+            Err(err) => {
+                logging::error(&err.to_string());
+                return Err(ApplicationError {
+                    kind: ApplicationErrorType::ConnectionError,
+                    retcode: 14,
+                });
+            }
+        };
+
+        let mut app = Application::new(app_state, &mut x11_client)?;
+
+        return daemon.run(&mut app);
+    });
+    // This is the actual application that'll start in its own thread. We'll control it from
+    // this thread based on the signals, but it keeps running.
+    // This is called after all the signals got registered, to avoid the short race condition
+    // in the first registration of each signal in multi-threaded programs.
+
+    // Consume all the incoming signals. This happens in "normal" Rust thread, not in the
+    // signal handlers. This means that we are allowed to do whatever we like in here, without
+    // restrictions, but it also means the kernel believes the signal already got delivered, we
+    // handle them in delayed manner. This is in contrast with eg the above
+    // `register_conditional_shutdown` where the shutdown happens *inside* the handler.
+    for info in &mut signals {
+        match info.signal {
+            // The new configuration replaces the old one as a whole, and only once it has been
+            // read and validated completely.
+            SIGHUP => match load_config(&config_path) {
+                Ok(new_config) => {
+                    *state.config.lock().unwrap() = new_config;
+                    state.wakeup.wake();
+                    logging::info(&format!("reloaded {}", config_path.display()));
+                }
+                Err(message) => {
+                    logging::error(&format!("keeping previous configuration: {}", message))
+                }
+            },
+            SIGUSR1 => {
+                let statistics = state.statistics.lock().unwrap();
+                let proc_win_info = state.proc_win_info.lock().unwrap();
+                for line in statistics.report(&proc_win_info) {
+                    logging::info(&line);
+                }
+            }
+            SIGTSTP => {
+                state.set_paused(true);
+                logging::info("tracking paused");
+            }
+            SIGCONT => {
+                state.set_paused(false);
+                logging::info("tracking resumed");
+            }
+            sig if TERM_SIGNALS.contains(&sig) => {
+                state.stop();
+                break;
+            }
+            _ => {}
         }
     }
+
+    match daemon_thread.join() {
+        Ok(Ok(_)) => process::exit(0),
+        Ok(Err(err)) => process::exit(err.retcode),
+        Err(_) => process::exit(1),
+    }
 }
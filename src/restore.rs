@@ -0,0 +1,440 @@
+use std::collections::{HashSet, VecDeque};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sysinfo::Pid;
+use xcb::Xid;
+
+use crate::config::DesktopPolicy;
+use crate::info::{process_command, Desktop, DisplayLayout, WindowType};
+use crate::session::{SessionSnapshot, WindowSnapshot};
+use crate::x11_client::X11Client;
+
+pub const DEFAULT_RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long the window manager gets to add or remove desktops before windows are placed.
+const DESKTOPS_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct RestoreReport {
+    pub launched: Vec<String>,
+    pub failed: Vec<String>,
+    pub placed: usize,
+    pub unmatched: usize,
+    /// Windows saved without a known owning process, which can't be relaunched.
+    pub skipped: usize,
+}
+
+struct PendingProcess {
+    command: Vec<String>,
+    cmdline: String,
+    process_id: Pid,
+    windows: VecDeque<WindowSnapshot>,
+}
+
+pub struct RestoreEngine<'a> {
+    x11_client: &'a X11Client<'a>,
+    timeout: Duration,
+    desktop_policy: DesktopPolicy,
+}
+
+impl<'a> RestoreEngine<'a> {
+    pub fn new(
+        x11_client: &'a X11Client<'a>,
+        timeout: Duration,
+        desktop_policy: DesktopPolicy,
+    ) -> Self {
+        return RestoreEngine {
+            x11_client,
+            timeout,
+            desktop_policy,
+        };
+    }
+
+    pub fn restore(self: &mut Self, snapshot: &SessionSnapshot) -> RestoreReport {
+        let mut report = RestoreReport::default();
+
+        let mut known_windows: HashSet<u32> = self
+            .x11_client
+            .get_wm_clients()
+            .iter()
+            .map(|w| w.resource_id())
+            .collect();
+
+        let layout = match snapshot.layout.as_ref() {
+            Some(saved) => self.prepare_desktops(saved),
+            None => self.x11_client.get_display_layout(),
+        };
+
+        let mut pending = Vec::new();
+        report.skipped = snapshot
+            .processes
+            .iter()
+            .filter(|p| p.process_id.is_none())
+            .map(|p| p.windows.len())
+            .sum();
+
+        for (command, cmdline, windows) in Self::group_by_process(snapshot) {
+            match Self::launch(&command) {
+                Some(process_id) => {
+                    report.launched.push(command.join(" "));
+                    pending.push(PendingProcess {
+                        command,
+                        cmdline,
+                        process_id,
+                        windows,
+                    });
+                }
+                None => report.failed.push(command.join(" ")),
+            }
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        while pending.iter().any(|p| !p.windows.is_empty()) && Instant::now() < deadline {
+            for window in self.x11_client.get_wm_clients() {
+                if !known_windows.insert(window.resource_id()) {
+                    continue;
+                }
+
+                // The window may not be fully set up yet, try again on the next round instead of
+                // giving up on it.
+                let window_info = match self.x11_client.get_window_information(&window) {
+                    Ok(info) => info,
+                    Err(_) => {
+                        known_windows.remove(&window.resource_id());
+                        continue;
+                    }
+                };
+
+                let is_target = window_info.x11_window_type == WindowType::Normal
+                    && window_info.x11_transient_for.is_none();
+                if !is_target {
+                    continue;
+                }
+
+                let owner = match Self::find_owner(
+                    &pending,
+                    window_info.process_id,
+                    window_info.x11_icccm.wm_command.as_deref(),
+                ) {
+                    Some(index) => &mut pending[index],
+                    None => continue,
+                };
+
+                // Editors and the like show the open file in the title, which tells their windows
+                // apart better than the order they come up in.
+                let index = owner
+                    .windows
+                    .iter()
+                    .position(|w| w.window_name == window_info.x11_window_name)
+                    .unwrap_or(0);
+                let saved = owner.windows.remove(index).unwrap();
+                // Sticky windows are re-stuck through the same request with the all-desktops value.
+                if let Some(desktop) = Self::map_desktop(&layout, &saved) {
+                    self.x11_client.move_window_to_desktop(&window, desktop);
+                }
+                if let Some(geometry) = saved.geometry.as_ref() {
+                    self.x11_client.move_resize_window(&window, geometry);
+                }
+                for state in saved.states.iter() {
+                    self.x11_client.add_window_state(&window, *state);
+                }
+                report.placed += 1;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        report.unmatched = pending.iter().map(|p| p.windows.len()).sum();
+        return report;
+    }
+
+    // Desktops are recreated before anything is launched, so applications that pick their
+    // desktop themselves find them as well. The layout the window manager ended up with is
+    // returned, it doesn't have to honour the requests.
+    fn prepare_desktops(self: &Self, saved: &DisplayLayout) -> DisplayLayout {
+        let current = self.x11_client.get_display_layout();
+        let count = match self.desktop_policy {
+            DesktopPolicy::Keep => current.desktops.len(),
+            DesktopPolicy::Grow => current.desktops.len().max(saved.desktops.len()),
+            DesktopPolicy::Match if saved.desktops.is_empty() => current.desktops.len(),
+            DesktopPolicy::Match => saved.desktops.len(),
+        };
+
+        if count != current.desktops.len() {
+            self.x11_client.set_number_of_desktops(count as u32);
+            let deadline = Instant::now() + DESKTOPS_TIMEOUT;
+            while self.x11_client.get_number_of_desktops() as usize != count
+                && Instant::now() < deadline
+            {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        // Desktops the session didn't name keep the names they have now.
+        let mut names = current.desktops.clone();
+        names.resize(count, String::new());
+        for (name, saved_name) in names.iter_mut().zip(saved.desktops.iter()) {
+            if !saved_name.is_empty() {
+                name.clone_from(saved_name);
+            }
+        }
+        if names != current.desktops {
+            self.x11_client.set_desktop_names(&names);
+        }
+
+        return self.x11_client.get_display_layout();
+    }
+
+    // Desktop numbers shift when desktops are added or removed in between, names are a better
+    // guide where there are any.
+    fn map_desktop(layout: &DisplayLayout, saved: &WindowSnapshot) -> Option<u32> {
+        let number = match saved.desktop {
+            Desktop::Number(number) => number,
+            Desktop::All | Desktop::Unknown => return saved.desktop.to_net_wm_desktop(),
+        };
+
+        if !saved.desktop_name.is_empty() {
+            match layout
+                .desktops
+                .iter()
+                .position(|name| *name == saved.desktop_name)
+            {
+                Some(index) => return Some(index as u32),
+                None => {}
+            }
+        }
+
+        // Not every window manager announces its desktops, then the saved number is all there is.
+        if layout.desktops.is_empty() || (number as usize) < layout.desktops.len() {
+            return Some(number);
+        }
+        return None;
+    }
+
+    // Every saved process is relaunched on its own, two terminals with a window each have to come
+    // back as two terminals. Only entries recorded for the same process share a launch.
+    // Single-instance applications that hand new windows to a running instance are still
+    // matched through WM_COMMAND when the windows come up.
+    fn group_by_process(
+        snapshot: &SessionSnapshot,
+    ) -> Vec<(Vec<String>, String, VecDeque<WindowSnapshot>)> {
+        let mut groups: Vec<(usize, Vec<String>, String, VecDeque<WindowSnapshot>)> = Vec::new();
+
+        for process in snapshot.processes.iter() {
+            let process_id = match process.process_id {
+                Some(process_id) => process_id,
+                None => continue,
+            };
+            let command = process.launch_command();
+            let targets = process
+                .windows
+                .iter()
+                .filter(|w| w.is_restore_target())
+                .cloned();
+            if command.is_empty() || !process.windows.iter().any(|w| w.is_restore_target()) {
+                continue;
+            }
+
+            match groups.iter_mut().find(|(p, _, _, _)| *p == process_id) {
+                Some((_, _, _, windows)) => windows.extend(targets),
+                None => groups.push((
+                    process_id,
+                    command,
+                    process.cmdline.clone(),
+                    targets.collect(),
+                )),
+            }
+        }
+
+        return groups
+            .into_iter()
+            .map(|(_, command, cmdline, windows)| (command, cmdline, windows))
+            .collect();
+    }
+
+    fn launch(command: &[String]) -> Option<Pid> {
+        let (program, args) = command.split_first()?;
+
+        let mut child = match Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(_) => return None,
+        };
+
+        let process_id = Pid::from_u32(child.id());
+        thread::spawn(move || child.wait());
+
+        return Some(process_id);
+    }
+
+    // Launchers and single-instance applications often hand their window over to another
+    // process, so fall back to matching WM_COMMAND and then the command line when the PID is not
+    // one we spawned.
+    fn find_owner(
+        pending: &[PendingProcess],
+        process_id: Option<Pid>,
+        wm_command: Option<&[String]>,
+    ) -> Option<usize> {
+        let waiting = |p: &PendingProcess| !p.windows.is_empty();
+
+        match pending
+            .iter()
+            .position(|p| waiting(p) && Some(p.process_id) == process_id)
+        {
+            Some(index) => return Some(index),
+            None => {}
+        }
+
+        match wm_command {
+            Some(command) if !command.is_empty() => {
+                match pending
+                    .iter()
+                    .position(|p| waiting(p) && p.command == command)
+                {
+                    Some(index) => return Some(index),
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+
+        // An empty command line says nothing about a process, matching on it would claim any
+        // window that happens to come up during the restore.
+        let cmdline = process_command(process_id?)?.join(" ");
+        if cmdline.is_empty() {
+            return None;
+        }
+
+        return pending
+            .iter()
+            .position(|p| waiting(p) && !p.cmdline.is_empty() && p.cmdline == cmdline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(desktops: &[&str]) -> DisplayLayout {
+        return DisplayLayout {
+            desktops: desktops.iter().map(|name| name.to_string()).collect(),
+            ..DisplayLayout::default()
+        };
+    }
+
+    fn saved_window(desktop_name: &str, desktop: &str) -> WindowSnapshot {
+        let data = format!(
+            r#"{{"window_name": "window", "window_xid": 1, "desktop_name": "{}", "desktop": {}}}"#,
+            desktop_name, desktop
+        );
+        return serde_json::from_str(&data).unwrap();
+    }
+
+    #[test]
+    fn maps_desktops_by_name_first() {
+        // "mail" moved from the second to the third desktop.
+        let layout = layout(&["web", "code", "mail"]);
+        assert_eq!(
+            RestoreEngine::map_desktop(&layout, &saved_window("mail", "1")),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_desktop_number() {
+        let layout = layout(&["web", "code", "mail"]);
+        assert_eq!(
+            RestoreEngine::map_desktop(&layout, &saved_window("chat", "1")),
+            Some(1)
+        );
+        assert_eq!(
+            RestoreEngine::map_desktop(&layout, &saved_window("", "0")),
+            Some(0)
+        );
+        assert_eq!(
+            RestoreEngine::map_desktop(&layout, &saved_window("chat", "3")),
+            None
+        );
+
+        // Without announced desktops the saved number is used as it is.
+        assert_eq!(
+            RestoreEngine::map_desktop(&DisplayLayout::default(), &saved_window("chat", "5")),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn maps_special_desktops() {
+        let layout = layout(&["web"]);
+        assert_eq!(
+            RestoreEngine::map_desktop(&layout, &saved_window("", "\"all\"")),
+            Some(0xFFFFFFFF)
+        );
+        assert_eq!(
+            RestoreEngine::map_desktop(&layout, &saved_window("web", "\"unknown\"")),
+            None
+        );
+    }
+
+    fn saved_process(process_id: usize, command: &str, windows: &[u32]) -> String {
+        let windows: Vec<String> = windows
+            .iter()
+            .map(|xid| {
+                format!(
+                    r#"{{"window_name": "window", "window_xid": {}, "desktop_name": "", "desktop": 0}}"#,
+                    xid
+                )
+            })
+            .collect();
+        return format!(
+            r#"{{"cmdline": "{}", "process_id": {}, "windows": [{}]}}"#,
+            command,
+            process_id,
+            windows.join(", ")
+        );
+    }
+
+    fn snapshot(processes: &[String]) -> SessionSnapshot {
+        let data = format!(
+            r#"{{"version": 2, "timestamp": "2024-01-01T12:00:00+00:00", "processes": [{}]}}"#,
+            processes.join(", ")
+        );
+        return serde_json::from_str(&data).unwrap();
+    }
+
+    #[test]
+    fn launches_every_saved_process() {
+        let snapshot = snapshot(&[
+            saved_process(10, "xterm", &[1]),
+            saved_process(11, "xterm", &[2]),
+        ]);
+
+        let groups = RestoreEngine::group_by_process(&snapshot);
+        assert_eq!(groups.len(), 2);
+        assert!(groups
+            .iter()
+            .all(|(command, _, windows)| *command == vec!["xterm"] && windows.len() == 1));
+    }
+
+    #[test]
+    fn launches_one_process_once() {
+        let snapshot = snapshot(&[
+            saved_process(10, "editor", &[1, 2]),
+            saved_process(10, "editor", &[3]),
+        ]);
+
+        let groups = RestoreEngine::group_by_process(&snapshot);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].2.len(), 3);
+    }
+}
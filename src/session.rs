@@ -0,0 +1,616 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ExcludeRule;
+use crate::info::{
+    Desktop, DisplayLayout, IcccmProperties, ProcessInfo, ProcessesWindowsInfo, TitleChange,
+    WindowGeometry, WindowInfo, WindowState, WindowType,
+};
+use crate::logging;
+use crate::xdg;
+
+// Only bumped for changes older readers can't cope with; new optional fields default instead.
+// Version 2 replaced `desktop_number` with `desktop`, which also reads the old field.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+const DEFAULT_SNAPSHOT_NAME: &str = "session.json";
+const NAMED_SESSIONS_DIRECTORY: &str = "sessions";
+const AUTOSAVE_DIRECTORY: &str = "autosave";
+
+#[derive(Debug)]
+pub enum SessionErrorType {
+    Io,
+    Serialize,
+    Deserialize,
+    UnsupportedVersion,
+    InvalidName,
+    NotFound,
+    AlreadyExists,
+}
+
+#[derive(Debug)]
+pub struct SessionError {
+    pub kind: SessionErrorType,
+    pub message: String,
+}
+
+impl SessionError {
+    pub fn new(kind: SessionErrorType, message: String) -> Self {
+        return SessionError { kind, message };
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:?}: {}", self.kind, self.message);
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        return SessionError::new(SessionErrorType::Io, err.to_string());
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WindowSnapshot {
+    pub window_name: String,
+    pub window_xid: u32,
+    pub desktop_name: String,
+    #[serde(alias = "desktop_number")]
+    pub desktop: Desktop,
+    #[serde(default)]
+    pub geometry: Option<WindowGeometry>,
+    #[serde(default)]
+    pub states: BTreeSet<WindowState>,
+    #[serde(default)]
+    pub icccm: IcccmProperties,
+    #[serde(default)]
+    pub window_type: WindowType,
+    #[serde(default)]
+    pub transient_for: Option<u32>,
+    /// The most recent titles, oldest first, ending with `window_name`.
+    #[serde(default)]
+    pub title_history: Vec<TitleChange>,
+}
+
+impl WindowSnapshot {
+    /// Dialogs, splash screens and the like are saved for completeness, but applications create
+    /// them on their own terms, so only normal top-level windows are waited for on restore.
+    pub fn is_restore_target(self: &Self) -> bool {
+        return self.window_type == WindowType::Normal && self.transient_for.is_none();
+    }
+}
+
+impl From<&WindowInfo> for WindowSnapshot {
+    fn from(window: &WindowInfo) -> Self {
+        return WindowSnapshot {
+            window_name: window.window_name.clone(),
+            window_xid: window.window_xid,
+            desktop_name: window.desktop_name.clone(),
+            desktop: window.desktop,
+            geometry: Some(window.geometry),
+            states: window.states.clone(),
+            icccm: window.icccm.clone(),
+            window_type: window.window_type,
+            transient_for: window.transient_for,
+            title_history: window.title_history.iter().cloned().collect(),
+        };
+    }
+}
+
+/// Where the command a process gets relaunched with came from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSource {
+    /// The process arguments read from /proc.
+    #[default]
+    Cmdline,
+    /// WM_COMMAND, which clients set specifically so session managers can restart them.
+    WmCommand,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    pub cmdline: String,
+    /// `None` for windows whose owner wasn't known, which are saved but never relaunched.
+    #[serde(default)]
+    pub process_id: Option<usize>,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub command_source: CommandSource,
+    pub windows: Vec<WindowSnapshot>,
+}
+
+impl ProcessSnapshot {
+    fn new<'a, I>(process: &ProcessInfo, windows: I) -> Self
+    where
+        I: Iterator<Item = &'a WindowInfo>,
+    {
+        let mut windows: Vec<WindowSnapshot> = windows.map(WindowSnapshot::from).collect();
+        windows.sort_by_key(|w| w.window_xid);
+
+        // WM_COMMAND survives setproctitle and wrapper scripts that leave the /proc command line
+        // useless, and keeps arguments containing spaces intact.
+        let wm_command = windows
+            .iter()
+            .filter_map(|w| w.icccm.wm_command.as_ref())
+            .find(|command| !command.is_empty());
+        let (command, command_source) = match wm_command {
+            // Windows of unknown owners are grouped together, so there is no one command for them.
+            _ if process.process_id.is_none() => (Vec::new(), CommandSource::Cmdline),
+            Some(command) => (command.clone(), CommandSource::WmCommand),
+            None => (process.command.clone(), CommandSource::Cmdline),
+        };
+
+        return ProcessSnapshot {
+            cmdline: process.cmdline.clone(),
+            process_id: process.process_id,
+            command,
+            command_source,
+            windows,
+        };
+    }
+
+    /// Snapshots from before the command was recorded only have the joined command line, which
+    /// is split on whitespace as the best guess there is.
+    pub fn launch_command(self: &Self) -> Vec<String> {
+        if !self.command.is_empty() {
+            return self.command.clone();
+        }
+        return self.cmdline.split_whitespace().map(String::from).collect();
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub version: u32,
+    pub timestamp: DateTime<Local>,
+    /// Missing in snapshots written before the layout was recorded.
+    #[serde(default)]
+    pub layout: Option<DisplayLayout>,
+    pub processes: Vec<ProcessSnapshot>,
+}
+
+/// What `sessiond sessions` shows about a named session without restoring it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub name: String,
+    pub timestamp: DateTime<Local>,
+    pub processes: usize,
+    pub windows: usize,
+    pub layout: Option<DisplayLayout>,
+}
+
+impl From<&ProcessesWindowsInfo> for SessionSnapshot {
+    fn from(info: &ProcessesWindowsInfo) -> Self {
+        let mut processes: Vec<ProcessSnapshot> = info
+            .procinfo
+            .iter()
+            .map(|(process, windows)| ProcessSnapshot::new(process, windows.values()))
+            .collect();
+        processes.sort_by_key(|p| p.process_id);
+
+        return SessionSnapshot {
+            version: SNAPSHOT_VERSION,
+            timestamp: Local::now(),
+            layout: None,
+            processes,
+        };
+    }
+}
+
+impl SessionSnapshot {
+    pub fn default_path(directory: &Path) -> PathBuf {
+        return directory.join(DEFAULT_SNAPSHOT_NAME);
+    }
+
+    pub fn named_path(directory: &Path, name: &str) -> Result<PathBuf, SessionError> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(SessionError::new(
+                SessionErrorType::InvalidName,
+                format!("'{}' is not a valid session name", name),
+            ));
+        }
+
+        return Ok(directory
+            .join(NAMED_SESSIONS_DIRECTORY)
+            .join(format!("{}.json", name)));
+    }
+
+    pub fn path_for(directory: &Path, name: Option<&str>) -> Result<PathBuf, SessionError> {
+        match name {
+            Some(name) => return Self::named_path(directory, name),
+            None => return Ok(Self::default_path(directory)),
+        }
+    }
+
+    /// Summaries of the named sessions sorted by name. Files that can't be read are skipped
+    /// rather than hiding every other session.
+    pub fn list_named(directory: &Path) -> Result<Vec<SessionSummary>, SessionError> {
+        let entries = match fs::read_dir(directory.join(NAMED_SESSIONS_DIRECTORY)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(SessionError::from(err)),
+        };
+
+        let mut sessions = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+
+            match Self::load(&path) {
+                Ok(snapshot) => sessions.push(SessionSummary {
+                    name,
+                    timestamp: snapshot.timestamp,
+                    processes: snapshot.processes.len(),
+                    windows: snapshot.window_count(),
+                    layout: snapshot.layout,
+                }),
+                Err(err) => logging::warn(&format!("skipping session: {}", err)),
+            }
+        }
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        return Ok(sessions);
+    }
+
+    pub fn rename_named(directory: &Path, from: &str, to: &str) -> Result<PathBuf, SessionError> {
+        let source = Self::existing_named_path(directory, from)?;
+        let destination = Self::named_path(directory, to)?;
+        if destination.exists() {
+            return Err(SessionError::new(
+                SessionErrorType::AlreadyExists,
+                format!("a session named '{}' already exists", to),
+            ));
+        }
+
+        fs::rename(&source, &destination)?;
+        return Ok(destination);
+    }
+
+    pub fn delete_named(directory: &Path, name: &str) -> Result<PathBuf, SessionError> {
+        let path = Self::existing_named_path(directory, name)?;
+        fs::remove_file(&path)?;
+        return Ok(path);
+    }
+
+    fn existing_named_path(directory: &Path, name: &str) -> Result<PathBuf, SessionError> {
+        let path = Self::named_path(directory, name)?;
+        if !path.exists() {
+            return Err(SessionError::new(
+                SessionErrorType::NotFound,
+                format!("no session named '{}'", name),
+            ));
+        }
+        return Ok(path);
+    }
+
+    /// Autosaves are numbered from the newest one, `autosave/0.json`.
+    pub fn autosave_path(directory: &Path, index: usize) -> PathBuf {
+        return directory
+            .join(AUTOSAVE_DIRECTORY)
+            .join(format!("{}.json", index));
+    }
+
+    /// Shifts the existing autosaves one slot back, dropping the ones beyond `keep`, and writes
+    /// the snapshot as the newest autosave.
+    pub fn save_autosave(
+        self: &Self,
+        directory: &Path,
+        keep: usize,
+    ) -> Result<PathBuf, SessionError> {
+        let keep = keep.max(1);
+
+        // Leftovers from a larger `keep` would otherwise linger forever.
+        let mut index = keep - 1;
+        while Self::autosave_path(directory, index).exists() {
+            fs::remove_file(Self::autosave_path(directory, index))?;
+            index += 1;
+        }
+
+        for index in (0..keep - 1).rev() {
+            match fs::rename(
+                Self::autosave_path(directory, index),
+                Self::autosave_path(directory, index + 1),
+            ) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(SessionError::from(err)),
+            }
+        }
+
+        let path = Self::autosave_path(directory, 0);
+        self.save(&path)?;
+        return Ok(path);
+    }
+
+    pub fn exclude(self: &mut Self, rules: &[ExcludeRule]) {
+        if rules.is_empty() {
+            return;
+        }
+
+        for process in self.processes.iter_mut() {
+            let cmdline = &process.cmdline;
+            process.windows.retain(|window| {
+                !rules
+                    .iter()
+                    .any(|rule| rule.matches(cmdline, &window.window_name))
+            });
+        }
+        self.processes.retain(|process| !process.windows.is_empty());
+    }
+
+    pub fn window_count(self: &Self) -> usize {
+        return self.processes.iter().map(|p| p.windows.len()).sum();
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SessionError> {
+        let data = fs::read(path)?;
+        let snapshot: SessionSnapshot = match serde_json::from_slice(&data) {
+            Ok(s) => s,
+            Err(err) => {
+                return Err(SessionError::new(
+                    SessionErrorType::Deserialize,
+                    format!("{}: {}", path.display(), err),
+                ))
+            }
+        };
+
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(SessionError::new(
+                SessionErrorType::UnsupportedVersion,
+                format!(
+                    "{}: snapshot version {} is newer than supported version {}",
+                    path.display(),
+                    snapshot.version,
+                    SNAPSHOT_VERSION
+                ),
+            ));
+        }
+
+        return Ok(snapshot);
+    }
+
+    /// Writes the snapshot next to its destination first and renames it into place, so a crash
+    /// mid-write never leaves a truncated session behind. Restoring runs the saved commands, so
+    /// snapshots and their directories are private to the user.
+    pub fn save(self: &Self, path: &Path) -> Result<(), SessionError> {
+        if let Some(parent) = path.parent() {
+            xdg::create_private_directory(parent)?;
+        }
+
+        let data = match serde_json::to_vec_pretty(self) {
+            Ok(d) => d,
+            Err(err) => {
+                return Err(SessionError::new(
+                    SessionErrorType::Serialize,
+                    err.to_string(),
+                ))
+            }
+        };
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        // A leftover from a crash keeps whatever mode it was created with, so it is replaced
+        // rather than reused.
+        match fs::remove_file(&temporary) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SessionError::from(err)),
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        file.write_all(&data)?;
+        fs::rename(&temporary, path)?;
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    fn temporary_directory(test: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("sessiond-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        return directory;
+    }
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        return arguments.iter().map(|arg| arg.to_string()).collect();
+    }
+
+    fn window(window_xid: u32, wm_command: Option<&[&str]>) -> WindowInfo {
+        return WindowInfo {
+            window_name: "window".to_string(),
+            window_xid,
+            desktop_name: "main".to_string(),
+            desktop: Desktop::Number(0),
+            geometry: WindowGeometry::default(),
+            states: BTreeSet::new(),
+            icccm: IcccmProperties {
+                wm_command: wm_command.map(arguments),
+                ..IcccmProperties::default()
+            },
+            window_type: WindowType::Normal,
+            transient_for: None,
+            title_history: VecDeque::new(),
+        };
+    }
+
+    #[test]
+    fn prefers_wm_command_over_the_process_command_line() {
+        let process = ProcessInfo::new(arguments(&["/usr/bin/python3", "editor"]), Some(42), None);
+        let windows = [
+            window(1, None),
+            window(2, Some(&["editor", "--new", "a b"])),
+        ];
+
+        let snapshot = ProcessSnapshot::new(&process, windows.iter());
+        assert_eq!(snapshot.command, vec!["editor", "--new", "a b"]);
+        assert_eq!(snapshot.command_source, CommandSource::WmCommand);
+        assert_eq!(snapshot.launch_command(), snapshot.command);
+    }
+
+    #[test]
+    fn ignores_an_empty_wm_command() {
+        let process = ProcessInfo::new(arguments(&["xterm", "-title", "a b"]), Some(42), None);
+        let windows = [window(1, Some(&[]))];
+
+        let snapshot = ProcessSnapshot::new(&process, windows.iter());
+        assert_eq!(snapshot.command, vec!["xterm", "-title", "a b"]);
+        assert_eq!(snapshot.command_source, CommandSource::Cmdline);
+    }
+
+    #[test]
+    fn rotates_autosaves() {
+        let directory = temporary_directory("autosave");
+        let mut snapshot = SessionSnapshot::from(&ProcessesWindowsInfo::new());
+
+        let mut timestamps = Vec::new();
+        for offset in 0..4 {
+            snapshot.timestamp = Local::now() + chrono::Duration::seconds(offset);
+            timestamps.push(snapshot.timestamp);
+            let path = snapshot.save_autosave(&directory, 3).unwrap();
+            assert_eq!(path, SessionSnapshot::autosave_path(&directory, 0));
+        }
+        let saved: Vec<DateTime<Local>> = (0..3)
+            .map(|index| {
+                let path = SessionSnapshot::autosave_path(&directory, index);
+                return SessionSnapshot::load(&path).unwrap().timestamp;
+            })
+            .collect();
+        assert_eq!(saved, vec![timestamps[3], timestamps[2], timestamps[1]]);
+        assert!(!SessionSnapshot::autosave_path(&directory, 3).exists());
+
+        // Lowering `keep` drops the autosaves beyond it.
+        snapshot.save_autosave(&directory, 1).unwrap();
+        let remaining = fs::read_dir(directory.join(AUTOSAVE_DIRECTORY))
+            .unwrap()
+            .count();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(remaining, 1);
+    }
+
+    #[test]
+    fn validates_session_names() {
+        let directory = Path::new("/state");
+        assert_eq!(
+            SessionSnapshot::named_path(directory, "work").unwrap(),
+            Path::new("/state/sessions/work.json")
+        );
+        assert_eq!(
+            SessionSnapshot::path_for(directory, None).unwrap(),
+            Path::new("/state/session.json")
+        );
+
+        for name in ["", ".hidden", "..", "a/b", "../escape"] {
+            assert!(matches!(
+                SessionSnapshot::named_path(directory, name),
+                Err(SessionError {
+                    kind: SessionErrorType::InvalidName,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_owners_get_no_command() {
+        let process = ProcessInfo::new(Vec::new(), None, None);
+        let windows = [window(1, Some(&["xterm"]))];
+
+        let snapshot = ProcessSnapshot::new(&process, windows.iter());
+        assert!(snapshot.command.is_empty());
+        assert!(snapshot.launch_command().is_empty());
+    }
+
+    #[test]
+    fn reads_version_1_snapshots() {
+        let data = r#"{
+            "version": 1,
+            "timestamp": "2024-01-01T12:00:00+00:00",
+            "processes": [{
+                "cmdline": "xterm -e top",
+                "process_id": 42,
+                "windows": [{
+                    "window_name": "top",
+                    "window_xid": 1234,
+                    "desktop_name": "web",
+                    "desktop_number": 2
+                }]
+            }]
+        }"#;
+
+        let snapshot: SessionSnapshot = serde_json::from_str(data).unwrap();
+        let window = &snapshot.processes[0].windows[0];
+        assert_eq!(window.desktop, Desktop::Number(2));
+        assert_eq!(window.window_type, WindowType::Normal);
+        assert!(window.is_restore_target());
+        assert_eq!(
+            snapshot.processes[0].launch_command(),
+            vec!["xterm", "-e", "top"]
+        );
+    }
+
+    #[test]
+    fn rejects_newer_snapshot_versions() {
+        let directory = temporary_directory("newer-version");
+        let path = directory.join("newer.json");
+        let mut snapshot = SessionSnapshot::from(&ProcessesWindowsInfo::new());
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        snapshot.save(&path).unwrap();
+
+        let result = SessionSnapshot::load(&path);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(matches!(
+            result,
+            Err(SessionError {
+                kind: SessionErrorType::UnsupportedVersion,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn keeps_autosaves_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = temporary_directory("autosave-mode");
+        let snapshot = SessionSnapshot::from(&ProcessesWindowsInfo::new());
+        let path = snapshot.save_autosave(&directory, 3).unwrap();
+        snapshot.save_autosave(&directory, 3).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let modes = (
+            mode(&directory),
+            mode(&directory.join(AUTOSAVE_DIRECTORY)),
+            mode(&path),
+            mode(&SessionSnapshot::autosave_path(&directory, 1)),
+        );
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(modes, (0o700, 0o700, 0o600, 0o600));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::info::{Desktop, DisplayLayout, ProcessesWindowsInfo};
+use crate::session::SessionSnapshot;
+use crate::wakeup::Wakeup;
+
+pub struct Statistics {
+    pub started: DateTime<Local>,
+    pub events_processed: u64,
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        return Statistics {
+            started: Local::now(),
+            events_processed: 0,
+        };
+    }
+
+    pub fn uptime_seconds(self: &Self) -> i64 {
+        return (Local::now() - self.started).num_seconds();
+    }
+
+    pub fn report(self: &Self, proc_win_info: &ProcessesWindowsInfo) -> Vec<String> {
+        let uptime = self.uptime_seconds();
+        let mut lines = vec![format!(
+            "statistics: up {}h {:02}m {:02}s, {} events processed, {} processes, {} windows",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            self.events_processed,
+            proc_win_info.procinfo.len(),
+            proc_win_info.window_count()
+        )];
+
+        // (desktop name, process ids, window count) per desktop
+        let mut desktops: BTreeMap<Desktop, (String, Vec<Option<usize>>, usize)> = BTreeMap::new();
+        for (process, windows) in proc_win_info.procinfo.iter() {
+            for window in windows.values() {
+                let desktop = desktops
+                    .entry(window.desktop)
+                    .or_insert_with(|| (window.desktop_name.clone(), Vec::new(), 0));
+                if !desktop.1.contains(&process.process_id) {
+                    desktop.1.push(process.process_id);
+                }
+                desktop.2 += 1;
+            }
+        }
+
+        for (number, (name, processes, windows)) in desktops.iter() {
+            lines.push(format!(
+                "statistics: desktop {} ({}): {} processes, {} windows",
+                number,
+                name,
+                processes.len(),
+                windows
+            ));
+        }
+
+        return lines;
+    }
+}
+
+/// Handles shared between the event loop, the control socket and the signal handling thread.
+#[derive(Clone)]
+pub struct DaemonState {
+    pub is_running: Arc<Mutex<bool>>,
+    pub is_paused: Arc<Mutex<bool>>,
+    pub config: Arc<Mutex<Config>>,
+    pub statistics: Arc<Mutex<Statistics>>,
+    pub proc_win_info: Arc<Mutex<ProcessesWindowsInfo>>,
+    pub layout: Arc<Mutex<DisplayLayout>>,
+    pub events: Arc<EventBus>,
+    pub wakeup: Arc<Wakeup>,
+}
+
+impl DaemonState {
+    pub fn new(is_running: Arc<Mutex<bool>>, config: Config) -> io::Result<Self> {
+        return Ok(DaemonState {
+            is_running,
+            is_paused: Arc::new(Mutex::new(false)),
+            config: Arc::new(Mutex::new(config)),
+            statistics: Arc::new(Mutex::new(Statistics::new())),
+            proc_win_info: Arc::new(Mutex::new(ProcessesWindowsInfo::new())),
+            layout: Arc::new(Mutex::new(DisplayLayout::default())),
+            events: Arc::new(EventBus::new()),
+            wakeup: Arc::new(Wakeup::new()?),
+        });
+    }
+
+    /// Snapshot of the tracked windows as they would be saved, with the configured exclusions
+    /// already applied.
+    pub fn snapshot(self: &Self) -> SessionSnapshot {
+        let mut snapshot = SessionSnapshot::from(&*self.proc_win_info.lock().unwrap());
+        snapshot.layout = Some(self.layout.lock().unwrap().clone());
+        snapshot.exclude(&self.config.lock().unwrap().exclude);
+        return snapshot;
+    }
+
+    pub fn stop(self: &Self) {
+        *self.is_running.lock().unwrap() = false;
+        self.wakeup.wake();
+    }
+
+    pub fn set_paused(self: &Self, paused: bool) {
+        *self.is_paused.lock().unwrap() = paused;
+        self.wakeup.wake();
+    }
+}
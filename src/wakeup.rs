@@ -0,0 +1,40 @@
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Self-pipe used to interrupt the event loop while it sleeps on the X connection, so that other
+/// threads can hand it work without waiting for the next X event.
+pub struct Wakeup {
+    reader: UnixStream,
+    writer: UnixStream,
+}
+
+impl Wakeup {
+    pub fn new() -> io::Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+
+        return Ok(Wakeup { reader, writer });
+    }
+
+    pub fn wake(self: &Self) {
+        // A full socket buffer already guarantees a pending wakeup.
+        let _ = (&self.writer).write(&[1]);
+    }
+
+    pub fn drain(self: &Self) {
+        let mut buffer = [0u8; 64];
+        while let Ok(n) = (&self.reader).read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Wakeup {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.reader.as_raw_fd();
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::BTreeSet;
 use std::convert::From;
+use std::env;
+use std::fmt;
 
 use sysinfo::Pid;
+use xcb::x::Drawable as X11Drawable;
 use xcb::x::Window as X11Window;
 use xcb::Connection as X11Connection;
 use xcb::Xid;
 use xcb_wm::ewmh::Connection as EWMHConnection;
-use xcb_wm::icccm::Connection as ICCCMConnection;
+
+use crate::info::{
+    Desktop, DisplayLayout, IcccmProperties, WindowGeometry, WindowState, WindowType, WmClass,
+};
 
 enum ClientErrorType {
     Connection,
@@ -13,17 +20,31 @@ enum ClientErrorType {
 
 pub struct ClientError {
     err_type: ClientErrorType,
+    message: String,
 }
 
 impl ClientError {
-    pub fn new(err: ClientErrorType) -> Self {
-        return ClientError { err_type: err };
+    fn new(err: ClientErrorType, message: String) -> Self {
+        return ClientError {
+            err_type: err,
+            message,
+        };
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.err_type {
+            ClientErrorType::Connection => {
+                return write!(f, "cannot connect to the X server: {}", self.message)
+            }
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum GatherInfoErrorType {
-    WindowDesktopNumber,
+    WindowGeometry,
 }
 
 #[derive(Debug)]
@@ -36,150 +57,776 @@ impl GatherInfoError {
         return GatherInfoError { err };
     }
 }
+
+impl fmt::Display for GatherInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.err {
+            GatherInfoErrorType::WindowGeometry => return write!(f, "window geometry unavailable"),
+        }
+    }
+}
+
+// EWMH source indication for requests coming from pagers and other direct user actions; window
+// managers apply those without the focus-stealing heuristics used for ordinary applications.
+const CLIENT_MESSAGE_SOURCE_PAGER: u32 = 2;
+
+// In 32-bit units, enough for the longest command lines seen in the wild.
+const MAX_TEXT_PROPERTY_LENGTH: u32 = 16 * 1024;
+
+const NET_WM_STATE_ADD: u32 = 1;
+const ICCCM_ICONIC_STATE: u32 = 3;
+
+xcb::atoms_struct! {
+    pub struct X11Atoms {
+        pub wm_change_state => b"WM_CHANGE_STATE" only_if_exists = false,
+        pub wm_window_role => b"WM_WINDOW_ROLE" only_if_exists = false,
+    }
+}
+
+// _NET_MOVERESIZE_WINDOW flags: north-west gravity with x, y, width and height all present.
+const MOVERESIZE_GRAVITY_NORTH_WEST: u32 = 1;
+const MOVERESIZE_ALL_VALUES: u32 = 0xF << 8;
+
 pub struct X11WindowInformation<'a> {
     pub x11_window: &'a X11Window,
     pub x11_resource_id: u32,
     pub x11_window_name: String,
-    pub x11_desktop_number: u32,
+    pub x11_desktop: Desktop,
     pub x11_desktop_name: String,
-    pub process_id: Pid,
+    pub x11_geometry: WindowGeometry,
+    pub x11_states: BTreeSet<WindowState>,
+    pub x11_icccm: IcccmProperties,
+    pub x11_window_type: WindowType,
+    pub x11_transient_for: Option<u32>,
+    /// `None` when neither the server nor the client could tell which local process owns the
+    /// window, e.g. for clients connected over the network.
+    pub process_id: Option<Pid>,
 }
 
-impl<'a> X11WindowInformation<'a> {
-    pub fn new(
-        window: &'a X11Window,
-        resource_id: u32,
-        name: String,
-        desktop_number: u32,
-        desktop_name: String,
-        pid: Pid,
-    ) -> Self {
-        return X11WindowInformation {
-            x11_window: window,
-            x11_resource_id: resource_id,
-            x11_window_name: name,
-            x11_desktop_number: desktop_number,
-            x11_desktop_name: desktop_name,
-            process_id: pid,
-        };
-    }
+struct GeometryRequests {
+    geometry: xcb::x::GetGeometryCookie,
+    translate: xcb::x::TranslateCoordinatesCookie,
+    frame_extents: xcb::x::GetPropertyCookie,
+}
+
+// Everything `get_windows_information` asks the server about one window.
+struct WindowRequests {
+    net_wm_name: xcb::x::GetPropertyCookie,
+    wm_name: xcb::x::GetPropertyCookie,
+    desktop: xcb::x::GetPropertyCookie,
+    geometry: GeometryRequests,
+    states: xcb::x::GetPropertyCookie,
+    wm_class: xcb::x::GetPropertyCookie,
+    wm_command: xcb::x::GetPropertyCookie,
+    client_machine: xcb::x::GetPropertyCookie,
+    window_role: xcb::x::GetPropertyCookie,
+    window_type: xcb::x::GetPropertyCookie,
+    transient_for: xcb::x::GetPropertyCookie,
+    client_ids: xcb::res::QueryClientIdsCookie,
+    net_wm_pid: xcb::x::GetPropertyCookie,
 }
 
 pub struct X11Client<'a> {
     pub x11_connection: X11Connection,
-    ewmh_connection: Option<EWMHConnection<'a>>,
-    icccm_connection: Option<ICCCMConnection<'a>>,
+    extensions: Option<X11ClientExtensions<'a>>,
+    atoms: Option<X11Atoms>,
 
     pub x11_screen: i32,
 }
 
 impl<'a> X11Client<'a> {
-    pub fn new() -> Self {
-        let (x11_con, x11_screen) = X11Connection::connect(None).unwrap();
-        return X11Client {
+    pub fn new() -> Result<Self, ClientError> {
+        let (x11_con, x11_screen) = match X11Connection::connect(None) {
+            Ok(connection) => connection,
+            Err(err) => {
+                return Err(ClientError::new(
+                    ClientErrorType::Connection,
+                    err.to_string(),
+                ))
+            }
+        };
+        return Ok(X11Client {
             x11_connection: x11_con,
-            ewmh_connection: None,
-            icccm_connection: None,
+            extensions: None,
+            atoms: None,
 
             x11_screen,
-        };
+        });
     }
 
-    pub fn connect(self: &'a mut Self) -> &Self {
-        self.ewmh_connection = Some(EWMHConnection::connect(&self.x11_connection));
-        self.icccm_connection = Some(ICCCMConnection::connect(&self.x11_connection));
+    pub fn connect(self: &'a mut Self) -> Result<&'a Self, ClientError> {
+        self.extensions = Some(X11ClientExtensions::new(&self.x11_connection));
+        self.atoms = match X11Atoms::intern_all(&self.x11_connection) {
+            Ok(atoms) => Some(atoms),
+            Err(err) => {
+                return Err(ClientError::new(
+                    ClientErrorType::Connection,
+                    err.to_string(),
+                ))
+            }
+        };
 
-        return self;
+        return Ok(self);
     }
 
     pub fn get_wm_clients(self: &Self) -> Vec<X11Window> {
-        let request_cookie = self.ewmh_connection.as_ref().unwrap().send_request(&xcb_wm::ewmh::proto::GetClientList);
-        return self.ewmh_connection.as_ref().unwrap().wait_for_reply(request_cookie).unwrap().clients;
+        let ewmh_con = &self.extensions.as_ref().unwrap().ewmh;
+        let request_cookie = ewmh_con.send_request(&xcb_wm::ewmh::proto::GetClientList);
+        return ewmh_con.wait_for_reply(request_cookie).unwrap().clients;
+    }
+
+    pub fn ewmh_atoms(self: &Self) -> &xcb_wm::ewmh::Atoms {
+        return &self.extensions.as_ref().unwrap().ewmh.atoms;
+    }
+
+    pub fn root_window(self: &Self) -> X11Window {
+        return self
+            .x11_connection
+            .get_setup()
+            .roots()
+            .nth(self.x11_screen as usize)
+            .unwrap()
+            .root();
+    }
+
+    pub fn get_display_layout(self: &Self) -> DisplayLayout {
+        let mut desktops = self.get_desktop_names();
+        desktops.resize(self.get_number_of_desktops() as usize, String::new());
+
+        return DisplayLayout {
+            display: env::var("DISPLAY").unwrap_or_default(),
+            screen: self.x11_screen,
+            desktops,
+            current_desktop: self.get_current_desktop(),
+        };
+    }
+
+    pub fn get_number_of_desktops(self: &Self) -> u32 {
+        return self
+            .get_root_cardinal(self.ewmh_atoms()._NET_NUMBER_OF_DESKTOPS)
+            .unwrap_or(0);
+    }
+
+    pub fn get_current_desktop(self: &Self) -> Option<u32> {
+        return self.get_root_cardinal(self.ewmh_atoms()._NET_CURRENT_DESKTOP);
+    }
+
+    fn get_root_cardinal(self: &Self, property: xcb::x::Atom) -> Option<u32> {
+        let reply = self
+            .x11_connection
+            .wait_for_reply(self.request_property(
+                &self.root_window(),
+                property,
+                xcb::x::ATOM_CARDINAL,
+                1,
+            ))
+            .ok()?;
+        return reply.value::<u32>().first().copied();
+    }
+
+    pub fn select_root_events(self: &Self) -> xcb::ProtocolResult<()> {
+        for screen in self.x11_connection.get_setup().roots() {
+            let cookie =
+                self.x11_connection
+                    .send_request_checked(&xcb::x::ChangeWindowAttributes {
+                        window: screen.root(),
+                        value_list: &[xcb::x::Cw::EventMask(
+                            xcb::x::EventMask::SUBSTRUCTURE_NOTIFY
+                                | xcb::x::EventMask::PROPERTY_CHANGE,
+                        )],
+                    });
+            self.x11_connection.check_request(cookie)?;
+        }
+
+        return Ok(());
+    }
+
+    pub fn move_window_to_desktop(self: &Self, window: &X11Window, desktop: u32) {
+        let ewmh_con = &self.extensions.as_ref().unwrap().ewmh;
+        self.send_client_message(
+            window,
+            ewmh_con.atoms._NET_WM_DESKTOP,
+            [desktop, CLIENT_MESSAGE_SOURCE_PAGER, 0, 0, 0],
+        );
+    }
+
+    /// Asks the window manager for a different number of desktops, which it may refuse.
+    pub fn set_number_of_desktops(self: &Self, count: u32) {
+        self.send_client_message(
+            &self.root_window(),
+            self.ewmh_atoms()._NET_NUMBER_OF_DESKTOPS,
+            [count, 0, 0, 0, 0],
+        );
+    }
+
+    // Unlike the number of desktops, their names are set by pagers directly on the root window.
+    pub fn set_desktop_names(self: &Self, names: &[String]) {
+        let mut data = Vec::new();
+        for name in names.iter() {
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+
+        let ewmh = self.ewmh_atoms();
+        self.x11_connection.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: self.root_window(),
+            property: ewmh._NET_DESKTOP_NAMES,
+            r#type: ewmh.UTF8_STRING,
+            data: &data,
+        });
+        let _ = self.x11_connection.flush();
+    }
+
+    pub fn move_resize_window(self: &Self, window: &X11Window, geometry: &WindowGeometry) {
+        let ewmh_con = &self.extensions.as_ref().unwrap().ewmh;
+        self.send_client_message(
+            window,
+            ewmh_con.atoms._NET_MOVERESIZE_WINDOW,
+            [
+                MOVERESIZE_GRAVITY_NORTH_WEST
+                    | MOVERESIZE_ALL_VALUES
+                    | (CLIENT_MESSAGE_SOURCE_PAGER << 12),
+                geometry.x as u32,
+                geometry.y as u32,
+                geometry.width,
+                geometry.height,
+            ],
+        );
+    }
+
+    pub fn add_window_state(self: &Self, window: &X11Window, state: WindowState) {
+        // Window managers generally refuse to set _NET_WM_STATE_HIDDEN on request, iconifying
+        // goes through the ICCCM WM_CHANGE_STATE message instead.
+        if state == WindowState::Hidden {
+            let atoms = self.atoms.as_ref().unwrap();
+            self.send_client_message(
+                window,
+                atoms.wm_change_state,
+                [ICCCM_ICONIC_STATE, 0, 0, 0, 0],
+            );
+            return;
+        }
+
+        self.send_client_message(
+            window,
+            self.ewmh_atoms()._NET_WM_STATE,
+            [
+                NET_WM_STATE_ADD,
+                self.state_atom(state).resource_id(),
+                0,
+                CLIENT_MESSAGE_SOURCE_PAGER,
+                0,
+            ],
+        );
+    }
+
+    /// Selects structure and property events on the windows before gathering their information,
+    /// so nothing that changes in between goes unnoticed. Like `get_windows_information`, all
+    /// requests are sent before the first reply is waited for.
+    pub fn track_windows(
+        self: &'a Self,
+        windows: &'a [X11Window],
+    ) -> Vec<Result<X11WindowInformation<'a>, GatherInfoError>> {
+        let selections: Vec<xcb::VoidCookieChecked> = windows
+            .iter()
+            .map(|window| {
+                self.x11_connection
+                    .send_request_checked(&xcb::x::ChangeWindowAttributes {
+                        window: *window,
+                        value_list: &[xcb::x::Cw::EventMask(
+                            xcb::x::EventMask::STRUCTURE_NOTIFY
+                                | xcb::x::EventMask::PROPERTY_CHANGE,
+                        )],
+                    })
+            })
+            .collect();
+
+        let information = self.get_windows_information(windows);
+
+        // A window that is gone by now fails to give its information as well, the selections
+        // only have to be collected. Their replies came in before the information's.
+        for cookie in selections {
+            let _ = self.x11_connection.check_request(cookie);
+        }
+
+        return information;
+    }
+
+    fn send_client_message(
+        self: &Self,
+        window: &X11Window,
+        message_type: xcb::x::Atom,
+        data: [u32; 5],
+    ) {
+        let event = xcb::x::ClientMessageEvent::new(
+            *window,
+            message_type,
+            xcb::x::ClientMessageData::Data32(data),
+        );
+
+        self.x11_connection.send_request(&xcb::x::SendEvent {
+            propagate: false,
+            destination: xcb::x::SendEventDest::Window(self.root_window()),
+            event_mask: xcb::x::EventMask::SUBSTRUCTURE_NOTIFY
+                | xcb::x::EventMask::SUBSTRUCTURE_REDIRECT,
+            event: &event,
+        });
+        let _ = self.x11_connection.flush();
+    }
+
+    /// Gathers the information of many windows in one pass: every request for every window is
+    /// sent before the first reply is waited for, so the scan costs about one round-trip instead
+    /// of a dozen per window.
+    pub fn get_windows_information(
+        self: &'a Self,
+        windows: &'a [X11Window],
+    ) -> Vec<Result<X11WindowInformation<'a>, GatherInfoError>> {
+        let desktop_names = self.request_desktop_names();
+        let requests: Vec<WindowRequests> = windows
+            .iter()
+            .map(|window| self.request_window_information(window))
+            .collect();
+
+        let desktop_names = self.parse_desktop_names(desktop_names);
+        let hostname = nix::unistd::gethostname()
+            .ok()
+            .and_then(|name| name.into_string().ok());
+
+        return windows
+            .iter()
+            .zip(requests)
+            .map(|(window, requests)| {
+                self.collect_window_information(window, requests, &desktop_names, &hostname)
+            })
+            .collect();
     }
 
     pub fn get_window_information(
         self: &'a Self,
         window: &'a X11Window,
-    ) -> Result<X11WindowInformation, GatherInfoError> {
-        let desktop_number = match self.get_desktop_number_of_window(window) {
-            Ok(r) => r,
-            Err(e) => return Err(e),
+    ) -> Result<X11WindowInformation<'a>, GatherInfoError> {
+        return self
+            .get_windows_information(std::slice::from_ref(window))
+            .pop()
+            .unwrap();
+    }
+
+    fn request_window_information(self: &Self, window: &X11Window) -> WindowRequests {
+        let ewmh = self.ewmh_atoms();
+        let atoms = self.atoms.as_ref().unwrap();
+        let text = |property| {
+            return self.request_property(
+                window,
+                property,
+                xcb::x::ATOM_ANY,
+                MAX_TEXT_PROPERTY_LENGTH,
+            );
+        };
+
+        return WindowRequests {
+            net_wm_name: self.request_property(
+                window,
+                ewmh._NET_WM_NAME,
+                ewmh.UTF8_STRING,
+                MAX_TEXT_PROPERTY_LENGTH,
+            ),
+            wm_name: text(xcb::x::ATOM_WM_NAME),
+            desktop: self.request_property(window, ewmh._NET_WM_DESKTOP, xcb::x::ATOM_CARDINAL, 1),
+            geometry: self.request_geometry(window),
+            states: self.request_property(window, ewmh._NET_WM_STATE, xcb::x::ATOM_ATOM, 32),
+            wm_class: text(xcb::x::ATOM_WM_CLASS),
+            wm_command: text(xcb::x::ATOM_WM_COMMAND),
+            client_machine: text(xcb::x::ATOM_WM_CLIENT_MACHINE),
+            window_role: text(atoms.wm_window_role),
+            window_type: self.request_property(
+                window,
+                ewmh._NET_WM_WINDOW_TYPE,
+                xcb::x::ATOM_ATOM,
+                32,
+            ),
+            transient_for: self.request_property(
+                window,
+                xcb::x::ATOM_WM_TRANSIENT_FOR,
+                xcb::x::ATOM_WINDOW,
+                1,
+            ),
+            client_ids: self.request_client_ids(window),
+            net_wm_pid: self.request_property(window, ewmh._NET_WM_PID, xcb::x::ATOM_CARDINAL, 1),
+        };
+    }
+
+    // Every cookie is waited for, even the ones whose answer turns out not to be needed, so no
+    // reply is left queued on the connection.
+    fn collect_window_information(
+        self: &Self,
+        window: &'a X11Window,
+        requests: WindowRequests,
+        desktop_names: &[String],
+        hostname: &Option<String>,
+    ) -> Result<X11WindowInformation<'a>, GatherInfoError> {
+        let window_name = self.parse_window_name(requests.net_wm_name, requests.wm_name);
+        let desktop = self.parse_desktop(requests.desktop);
+        let geometry = self.parse_geometry(requests.geometry);
+        let states = self.parse_states(requests.states);
+        let icccm = IcccmProperties {
+            wm_class: self
+                .parse_text_property(requests.wm_class)
+                .filter(|values| !values.is_empty())
+                .map(|values| WmClass {
+                    instance: values[0].clone(),
+                    class: values.get(1).cloned().unwrap_or_default(),
+                }),
+            wm_command: self.parse_text_property(requests.wm_command),
+            client_machine: self
+                .parse_text_property(requests.client_machine)
+                .map(|values| values.join(" ")),
+            window_role: self
+                .parse_text_property(requests.window_role)
+                .map(|values| values.join(" ")),
         };
+        let transient_for = self.parse_transient_for(requests.transient_for);
+        let window_type = self.parse_window_type(requests.window_type, transient_for);
+        let client_pid = self.parse_client_ids(requests.client_ids);
+        let net_wm_pid = self.parse_net_wm_pid(requests.net_wm_pid);
+
+        // The X-Resource extension asks the server itself and can't be fooled by the client, but
+        // only works for local connections. _NET_WM_PID is whatever the client claims and only
+        // means something on the machine the client says it runs on.
+        let process_id = match client_pid {
+            Some(pid) => Some(pid),
+            None if hostname.is_some() && icccm.client_machine == *hostname => net_wm_pid,
+            None => None,
+        };
+
+        let desktop_name = match desktop {
+            Desktop::Number(number) => desktop_names
+                .get(number as usize)
+                .cloned()
+                .unwrap_or_default(),
+            Desktop::All | Desktop::Unknown => String::new(),
+        };
+
+        return Ok(X11WindowInformation {
+            x11_window: window,
+            x11_resource_id: window.resource_id(),
+            x11_window_name: window_name,
+            x11_desktop: desktop,
+            x11_desktop_name: desktop_name,
+            x11_geometry: geometry?,
+            x11_states: states,
+            x11_icccm: icccm,
+            x11_window_type: window_type,
+            x11_transient_for: transient_for,
+            process_id,
+        });
+    }
+
+    pub fn get_window_geometry(
+        self: &Self,
+        window: &X11Window,
+    ) -> Result<WindowGeometry, GatherInfoError> {
+        return self.parse_geometry(self.request_geometry(window));
+    }
 
-        return Ok(X11WindowInformation::new(
+    pub fn get_window_name(self: &Self, window: &X11Window) -> String {
+        let ewmh = self.ewmh_atoms();
+        let net_wm_name = self.request_property(
             window,
-            window.resource_id(),
-            self.get_window_name(window),
-            desktop_number,
-            self.get_desktop_name_of_window(desktop_number),
-            self.get_process_id_of_local_client(window),
+            ewmh._NET_WM_NAME,
+            ewmh.UTF8_STRING,
+            MAX_TEXT_PROPERTY_LENGTH,
+        );
+        let wm_name = self.request_property(
+            window,
+            xcb::x::ATOM_WM_NAME,
+            xcb::x::ATOM_ANY,
+            MAX_TEXT_PROPERTY_LENGTH,
+        );
+        return self.parse_window_name(net_wm_name, wm_name);
+    }
+
+    pub fn get_window_states(self: &Self, window: &X11Window) -> BTreeSet<WindowState> {
+        return self.parse_states(self.request_property(
+            window,
+            self.ewmh_atoms()._NET_WM_STATE,
+            xcb::x::ATOM_ATOM,
+            32,
         ));
     }
 
-    fn get_window_name(self: &Self, window: &X11Window) -> String {
-        let ewmh_con = self.ewmh_connection.as_ref().unwrap();
-        return ewmh_con
-            .wait_for_reply(ewmh_con.send_request(&xcb_wm::ewmh::proto::GetWmName(*window)))
-            .unwrap()
-            .name
-            .clone();
+    pub fn get_desktop_of_window(self: &Self, window: &X11Window) -> Desktop {
+        return self.parse_desktop(self.request_property(
+            window,
+            self.ewmh_atoms()._NET_WM_DESKTOP,
+            xcb::x::ATOM_CARDINAL,
+            1,
+        ));
     }
 
-    fn get_desktop_number_of_window(
+    pub fn get_desktop_names(self: &Self) -> Vec<String> {
+        return self.parse_desktop_names(self.request_desktop_names());
+    }
+
+    fn request_property(
         self: &Self,
         window: &X11Window,
-    ) -> Result<u32, GatherInfoError> {
-        let ewmh_con = self.ewmh_connection.as_ref().unwrap();
-
-        match ewmh_con
-            .wait_for_reply(ewmh_con.send_request(&xcb_wm::ewmh::proto::GetWmDesktop(*window)))
-        {
-            Ok(reply) => return Ok(reply.desktop),
-            Err(_) => {
-                return Err(GatherInfoError::new(
-                    GatherInfoErrorType::WindowDesktopNumber,
-                ))
-            }
-        }
+        property: xcb::x::Atom,
+        r#type: xcb::x::Atom,
+        long_length: u32,
+    ) -> xcb::x::GetPropertyCookie {
+        return self.x11_connection.send_request(&xcb::x::GetProperty {
+            delete: false,
+            window: *window,
+            property,
+            r#type,
+            long_offset: 0,
+            long_length,
+        });
     }
 
-    fn get_desktop_name_of_window(self: &Self, number: u32) -> String {
-        let ewmh_con = self.ewmh_connection.as_ref().unwrap();
-        let desktop_names = ewmh_con
-            .wait_for_reply(ewmh_con.send_request(&xcb_wm::ewmh::proto::GetDesktopNames))
-            .unwrap();
-        return desktop_names.names[usize::try_from(number).unwrap()].clone();
+    fn request_desktop_names(self: &Self) -> xcb::x::GetPropertyCookie {
+        let ewmh = self.ewmh_atoms();
+        return self.request_property(
+            &self.root_window(),
+            ewmh._NET_DESKTOP_NAMES,
+            ewmh.UTF8_STRING,
+            MAX_TEXT_PROPERTY_LENGTH,
+        );
     }
 
-    fn get_process_id_of_local_client(self: &Self, window: &X11Window) -> Pid {
+    fn request_geometry(self: &Self, window: &X11Window) -> GeometryRequests {
+        let ewmh = self.ewmh_atoms();
+        return GeometryRequests {
+            geometry: self.x11_connection.send_request(&xcb::x::GetGeometry {
+                drawable: X11Drawable::Window(*window),
+            }),
+            translate: self
+                .x11_connection
+                .send_request(&xcb::x::TranslateCoordinates {
+                    src_window: *window,
+                    dst_window: self.root_window(),
+                    src_x: 0,
+                    src_y: 0,
+                }),
+            frame_extents: self.request_property(
+                window,
+                ewmh._NET_FRAME_EXTENTS,
+                xcb::x::ATOM_CARDINAL,
+                4,
+            ),
+        };
+    }
+
+    fn request_client_ids(self: &Self, window: &X11Window) -> xcb::res::QueryClientIdsCookie {
         let bits: u32 = 0x02;
         let window_id_spec = xcb::res::ClientIdSpec {
             client: window.resource_id(),
             mask: xcb::res::ClientIdMask::from_bits(bits).unwrap(),
         };
 
-        let pid_reply = self
-            .x11_connection
-            .wait_for_reply(self.x11_connection.send_request(&xcb::res::QueryClientIds {
-                specs: &[window_id_spec],
-            }));
+        return self.x11_connection.send_request(&xcb::res::QueryClientIds {
+            specs: &[window_id_spec],
+        });
+    }
+
+    fn parse_geometry(
+        self: &Self,
+        requests: GeometryRequests,
+    ) -> Result<WindowGeometry, GatherInfoError> {
+        let geometry = self.x11_connection.wait_for_reply(requests.geometry);
+        let translated = self.x11_connection.wait_for_reply(requests.translate);
 
-        return Pid::from(
-            usize::try_from(pid_reply.unwrap().ids().next().unwrap().value()[0]).unwrap(),
+        // Left, right, top and bottom border widths added by the window manager's decorations.
+        let extents = match self.x11_connection.wait_for_reply(requests.frame_extents) {
+            Ok(reply) if reply.value::<u32>().len() == 4 => {
+                let values = reply.value::<u32>();
+                [values[0], values[1], values[2], values[3]]
+            }
+            _ => [0; 4],
+        };
+
+        match (geometry, translated) {
+            (Ok(geometry), Ok(translated)) => {
+                return Ok(WindowGeometry {
+                    x: i32::from(translated.dst_x()) - extents[0] as i32,
+                    y: i32::from(translated.dst_y()) - extents[2] as i32,
+                    width: u32::from(geometry.width()),
+                    height: u32::from(geometry.height()),
+                })
+            }
+            _ => return Err(GatherInfoError::new(GatherInfoErrorType::WindowGeometry)),
+        }
+    }
+
+    // _NET_WM_NAME is UTF-8, the legacy WM_NAME is only used by clients that don't set it.
+    fn parse_window_name(
+        self: &Self,
+        net_wm_name: xcb::x::GetPropertyCookie,
+        wm_name: xcb::x::GetPropertyCookie,
+    ) -> String {
+        let net_wm_name = self.parse_text_property(net_wm_name);
+        let wm_name = self.parse_text_property(wm_name);
+        return net_wm_name
+            .filter(|values| !values.is_empty())
+            .or(wm_name)
+            .map(|values| values.join(" "))
+            .unwrap_or_default();
+    }
+
+    fn parse_states(self: &Self, cookie: xcb::x::GetPropertyCookie) -> BTreeSet<WindowState> {
+        let mut states = BTreeSet::new();
+        match self.x11_connection.wait_for_reply(cookie) {
+            Ok(reply) => {
+                for atom in reply.value::<xcb::x::Atom>() {
+                    match WindowState::ALL
+                        .iter()
+                        .find(|state| self.state_atom(**state) == *atom)
+                    {
+                        Some(state) => {
+                            states.insert(*state);
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+
+        return states;
+    }
+
+    fn parse_desktop(self: &Self, cookie: xcb::x::GetPropertyCookie) -> Desktop {
+        match self.x11_connection.wait_for_reply(cookie) {
+            Ok(reply) if !reply.value::<u32>().is_empty() => {
+                return Desktop::from(reply.value::<u32>()[0])
+            }
+            _ => return Desktop::Unknown,
+        }
+    }
+
+    // xcb-wm drops empty names, which shifts every following name onto the wrong desktop.
+    fn parse_desktop_names(self: &Self, cookie: xcb::x::GetPropertyCookie) -> Vec<String> {
+        return self.parse_text_property(cookie).unwrap_or_default();
+    }
+
+    // ICCCM text properties hold one or more NUL-terminated strings. xcb-wm's parsers panic on
+    // missing elements and non-UTF-8 data, so the raw property is decoded here instead.
+    fn parse_text_property(self: &Self, cookie: xcb::x::GetPropertyCookie) -> Option<Vec<String>> {
+        let reply = self.x11_connection.wait_for_reply(cookie).ok()?;
+        if reply.r#type() == xcb::x::ATOM_NONE || reply.format() != 8 {
+            return None;
+        }
+
+        let data = reply.value::<u8>();
+        let data = data.strip_suffix(&[0]).unwrap_or(data);
+        if data.is_empty() {
+            return Some(Vec::new());
+        }
+
+        return Some(
+            data.split(|b| *b == 0)
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect(),
         );
     }
+
+    /// Windows without a type are normal windows, or dialogs when they are transient for another
+    /// window, as EWMH prescribes.
+    fn parse_window_type(
+        self: &Self,
+        cookie: xcb::x::GetPropertyCookie,
+        transient_for: Option<u32>,
+    ) -> WindowType {
+        // The list is in order of preference, the first type we know is the one to use.
+        match self.x11_connection.wait_for_reply(cookie) {
+            Ok(reply) => {
+                for atom in reply.value::<xcb::x::Atom>() {
+                    match WindowType::ALL
+                        .iter()
+                        .find(|window_type| self.window_type_atom(**window_type) == *atom)
+                    {
+                        Some(window_type) => return *window_type,
+                        None => {}
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+
+        match transient_for {
+            Some(_) => return WindowType::Dialog,
+            None => return WindowType::Normal,
+        }
+    }
+
+    fn parse_transient_for(self: &Self, cookie: xcb::x::GetPropertyCookie) -> Option<u32> {
+        let reply = self.x11_connection.wait_for_reply(cookie).ok()?;
+        let parent = reply.value::<xcb::x::Window>().first()?.resource_id();
+        if parent == 0 {
+            return None;
+        }
+        return Some(parent);
+    }
+
+    fn parse_client_ids(self: &Self, cookie: xcb::res::QueryClientIdsCookie) -> Option<Pid> {
+        let reply = self.x11_connection.wait_for_reply(cookie).ok()?;
+        let pid = *reply.ids().next()?.value().first()?;
+        return Some(Pid::from_u32(pid));
+    }
+
+    fn parse_net_wm_pid(self: &Self, cookie: xcb::x::GetPropertyCookie) -> Option<Pid> {
+        let reply = self.x11_connection.wait_for_reply(cookie).ok()?;
+        let pid = *reply.value::<u32>().first()?;
+        if pid == 0 {
+            return None;
+        }
+        return Some(Pid::from_u32(pid));
+    }
+
+    fn state_atom(self: &Self, state: WindowState) -> xcb::x::Atom {
+        let atoms = self.ewmh_atoms();
+        match state {
+            WindowState::MaximizedVert => return atoms._NET_WM_STATE_MAXIMIZED_VERT,
+            WindowState::MaximizedHorz => return atoms._NET_WM_STATE_MAXIMIZED_HORZ,
+            WindowState::Fullscreen => return atoms._NET_WM_STATE_FULLSCREEN,
+            WindowState::Sticky => return atoms._NET_WM_STATE_STICKY,
+            WindowState::Above => return atoms._NET_WM_STATE_ABOVE,
+            WindowState::Below => return atoms._NET_WM_STATE_BELOW,
+            WindowState::Hidden => return atoms._NET_WM_STATE_HIDDEN,
+            WindowState::Shaded => return atoms._NET_WM_STATE_SHADED,
+        }
+    }
+
+    fn window_type_atom(self: &Self, window_type: WindowType) -> xcb::x::Atom {
+        let atoms = self.ewmh_atoms();
+        match window_type {
+            WindowType::Desktop => return atoms._NET_WM_WINDOW_TYPE_DESKTOP,
+            WindowType::Dock => return atoms._NET_WM_WINDOW_TYPE_DOCK,
+            WindowType::Toolbar => return atoms._NET_WM_WINDOW_TYPE_TOOLBAR,
+            WindowType::Menu => return atoms._NET_WM_WINDOW_TYPE_MENU,
+            WindowType::Utility => return atoms._NET_WM_WINDOW_TYPE_UTILITY,
+            WindowType::Splash => return atoms._NET_WM_WINDOW_TYPE_SPLASH,
+            WindowType::Dialog => return atoms._NET_WM_WINDOW_TYPE_DIALOG,
+            WindowType::DropdownMenu => return atoms._NET_WM_WINDOW_TYPE_DROPDOWN_MENU,
+            WindowType::PopupMenu => return atoms._NET_WM_WINDOW_TYPE_POPUP_MENU,
+            WindowType::Tooltip => return atoms._NET_WM_WINDOW_TYPE_TOOLTIP,
+            WindowType::Notification => return atoms._NET_WM_WINDOW_TYPE_NOTIFICATION,
+            WindowType::Combo => return atoms._NET_WM_WINDOW_TYPE_COMBO,
+            WindowType::Dnd => return atoms._NET_WM_WINDOW_TYPE_DND,
+            WindowType::Normal => return atoms._NET_WM_WINDOW_TYPE_NORMAL,
+        }
+    }
 }
 
 struct X11ClientExtensions<'a> {
     ewmh: EWMHConnection<'a>,
-    icccm: ICCCMConnection<'a>,
 }
 
 impl<'a> X11ClientExtensions<'a> {
-    pub fn new(x11_con: &'a X11Connection) -> X11ClientExtensions {
+    pub fn new(x11_con: &'a X11Connection) -> X11ClientExtensions<'a> {
         return X11ClientExtensions {
             ewmh: EWMHConnection::connect(x11_con),
-            icccm: ICCCMConnection::connect(x11_con),
         };
     }
 }
@@ -0,0 +1,93 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+const APPLICATION_DIRECTORY: &str = "sessiond";
+
+fn base_directory(variable: &str, fallback: &str) -> PathBuf {
+    match env::var_os(variable) {
+        Some(dir) if !dir.is_empty() => return PathBuf::from(dir),
+        _ => {}
+    }
+
+    let home = env::var_os("HOME").unwrap_or_default();
+    return PathBuf::from(home).join(fallback);
+}
+
+pub fn state_directory() -> PathBuf {
+    return base_directory("XDG_STATE_HOME", ".local/state").join(APPLICATION_DIRECTORY);
+}
+
+pub fn config_directory() -> PathBuf {
+    return base_directory("XDG_CONFIG_HOME", ".config").join(APPLICATION_DIRECTORY);
+}
+
+pub fn runtime_directory() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => return PathBuf::from(dir).join(APPLICATION_DIRECTORY),
+        _ => {}
+    }
+
+    let uid = unsafe { libc::getuid() };
+    return env::temp_dir().join(format!("{}-{}", APPLICATION_DIRECTORY, uid));
+}
+
+pub fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => return PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(rest),
+        Err(_) => return path.to_path_buf(),
+    }
+}
+
+/// Creates `path` readable by the current user only, as the runtime directory may be a shared
+/// location such as /tmp.
+pub fn create_private_directory(path: &Path) -> io::Result<()> {
+    return fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path);
+}
+
+/// Creates the runtime directory and makes sure it is really ours. Outside XDG_RUNTIME_DIR it
+/// lives in /tmp, where another user may have created it first, and it is the only thing keeping
+/// others away from the control socket.
+pub fn create_runtime_directory(path: &Path) -> io::Result<()> {
+    create_private_directory(path)?;
+
+    let metadata = fs::symlink_metadata(path)?;
+    let uid = unsafe { libc::getuid() };
+    if !metadata.file_type().is_dir() || metadata.uid() != uid || metadata.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a directory owned by uid {} with mode 0700",
+                path.display(),
+                uid
+            ),
+        ));
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn refuses_runtime_directories_others_can_enter() {
+        let directory = env::temp_dir().join(format!("sessiond-runtime-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        create_runtime_directory(&directory).unwrap();
+        fs::set_permissions(&directory, fs::Permissions::from_mode(0o755)).unwrap();
+        let loose = create_runtime_directory(&directory);
+
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(loose.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+}